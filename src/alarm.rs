@@ -0,0 +1,411 @@
+//! One-shot alarms on the capture/compare channels a monotonic leaves free
+//!
+//! `split` on a monotonic hands out its free channels as `Alarm`s. Their callbacks are called
+//! from the monotonic's `on_interrupt`, so they share the interrupt of the RTIC timer queue.
+
+use crate::half_period::calc_now_bits;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU32, Ordering};
+use rtic_monotonic::Monotonic;
+
+/// Register level access to a capture/compare channel, shared by TIMER and RTC
+///
+/// Channel numbers must be below the `CC_CHANNELS` of the instance the registers belong to.
+pub trait CompareRegisters {
+    fn set_cc(&self, n: usize, value: u32);
+    fn is_compare(&self, n: usize) -> bool;
+    fn clear_compare(&self, n: usize);
+    fn enable_compare_interrupt(&self, n: usize);
+    fn disable_compare_interrupt(&self, n: usize);
+    fn is_compare_interrupt_enabled(&self, n: usize) -> bool;
+}
+
+/// Half-period count of a monotonic, shared with the alarms split off it
+///
+/// Every instance has one in a static, only its monotonic writes it. The count is 64 bits wide
+/// so it never wraps, but there are no 64-bit atomics: it is written to the slot not being read
+/// and published by incrementing `seq`. A read retries if `seq` changed meanwhile, and a read
+/// preempting the write sees the previous count.
+pub struct SharedPeriod {
+    /// Number of writes, its lowest bit selects the slot of the current count
+    seq: AtomicU32,
+    /// Lower and upper half of the count
+    slots: [[AtomicU32; 2]; 2],
+}
+
+impl SharedPeriod {
+    pub const fn new() -> Self {
+        Self {
+            seq: AtomicU32::new(0),
+            slots: [
+                [AtomicU32::new(0), AtomicU32::new(0)],
+                [AtomicU32::new(0), AtomicU32::new(0)],
+            ],
+        }
+    }
+
+    #[inline(always)]
+    pub fn get(&self) -> u64 {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            let [low, high] = &self.slots[seq as usize & 1];
+            let (low, high) = (low.load(Ordering::Acquire), high.load(Ordering::Acquire));
+            if self.seq.load(Ordering::Acquire) == seq {
+                return (high as u64) << 32 | low as u64;
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn set(&self, period: u64) {
+        let seq = self.seq.load(Ordering::Relaxed).wrapping_add(1);
+        let [low, high] = &self.slots[seq as usize & 1];
+        low.store(period as u32, Ordering::Relaxed);
+        high.store((period >> 32) as u32, Ordering::Relaxed);
+        self.seq.store(seq, Ordering::Release);
+    }
+}
+
+impl Default for SharedPeriod {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A monotonic whose free capture/compare channels can be split off into `Alarm`s
+///
+/// `Delay` and `AsyncDelay` use it to read the hardware counter of the monotonic.
+pub trait Split: Monotonic {
+    #[doc(hidden)]
+    type Registers: CompareRegisters + 'static;
+
+    /// Tick rate of the monotonic
+    #[doc(hidden)]
+    const HZ: u32;
+
+    /// Width of the hardware counter
+    #[doc(hidden)]
+    const COUNTER_BITS: u32;
+
+    /// A compare value closer than this to the counter may not generate an event
+    #[doc(hidden)]
+    const MIN_DISTANCE: u64;
+
+    /// Read the raw counter value
+    #[doc(hidden)]
+    fn counter(registers: &Self::Registers) -> u32;
+
+    #[doc(hidden)]
+    fn ticks(instant: Self::Instant) -> u64;
+}
+
+/// The requested instant is more than one counter period ahead
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct OutOfRange;
+
+/// A one-shot alarm on a capture/compare channel of the TIMER or RTC of monotonic `M`
+///
+/// The callback passed to `split` is called from `on_interrupt` of the monotonic when the alarm
+/// expires. Alarms have to be set after RTIC started the monotonic, `reset` cancels them.
+pub struct Alarm<M: Split> {
+    registers: &'static M::Registers,
+    period: &'static SharedPeriod,
+    channel: usize,
+    _monotonic: PhantomData<M>,
+}
+
+// SAFETY: an alarm only touches the registers of its own channel, and the interrupt enable
+// registers that are written with set and clear masks
+unsafe impl<M: Split> Send for Alarm<M> {}
+
+impl<M: Split> Alarm<M> {
+    pub(crate) fn new(
+        registers: &'static M::Registers,
+        period: &'static SharedPeriod,
+        channel: usize,
+    ) -> Self {
+        Self {
+            registers,
+            period,
+            channel,
+            _monotonic: PhantomData,
+        }
+    }
+
+    /// The capture/compare channel of this alarm
+    pub fn channel(&self) -> usize {
+        self.channel
+    }
+
+    pub(crate) fn now(&self) -> u64 {
+        // the period first: an interrupt in between makes it lag, which `calc_now` corrects
+        let period = self.period.get();
+        calc_now_bits(M::COUNTER_BITS, period, M::counter(self.registers))
+    }
+
+    /// Arm the alarm to expire at `instant`, replacing a pending one
+    ///
+    /// Instants in the past expire as soon as possible. At most one counter period ahead can be
+    /// reached, that is 2^32 ticks for a TIMER, 2^24 for an RTC and 2^16 in 16-bit mode.
+    pub fn set_at(&mut self, instant: M::Instant) -> Result<(), OutOfRange> {
+        self.set_at_ticks(M::ticks(instant))
+    }
+
+    pub(crate) fn set_at_ticks(&mut self, target: u64) -> Result<(), OutOfRange> {
+        let r = self.registers;
+        let mask = (1 << M::COUNTER_BITS) - 1;
+
+        r.disable_compare_interrupt(self.channel);
+        if target.saturating_sub(self.now()) > mask {
+            return Err(OutOfRange);
+        }
+
+        // The event is cleared after the new value is written, a match of the old one would
+        // count as expired otherwise. A match of the new value is cleared as well, so the value
+        // has to be ahead of the counter afterwards, else it is retried further ahead.
+        let mut distance = M::MIN_DISTANCE;
+        loop {
+            let at = target.max(self.now() + distance);
+            r.set_cc(self.channel, (at & mask) as u32);
+            r.clear_compare(self.channel);
+            if at >= self.now() + M::MIN_DISTANCE {
+                break;
+            }
+            distance *= 2;
+        }
+        trace!("Alarm {} set", self.channel);
+
+        r.enable_compare_interrupt(self.channel);
+        Ok(())
+    }
+
+    /// Disarm the alarm, its callback will not be called
+    pub fn cancel(&mut self) {
+        self.registers.disable_compare_interrupt(self.channel);
+        self.registers.clear_compare(self.channel);
+    }
+
+    /// Whether the alarm is armed and its callback has not been called yet
+    pub fn is_pending(&self) -> bool {
+        self.registers.is_compare_interrupt_enabled(self.channel)
+    }
+}
+
+/// Call the callback of every expired alarm, from `on_interrupt` of the monotonics
+///
+/// `callbacks[i]` belongs to the alarm on channel `first_channel + i`.
+#[inline(always)]
+pub(crate) fn handle_alarms(
+    registers: &impl CompareRegisters,
+    first_channel: usize,
+    callbacks: &[Option<fn()>],
+) {
+    for (i, callback) in callbacks.iter().enumerate() {
+        let n = first_channel + i;
+        if let Some(callback) = callback {
+            if registers.is_compare_interrupt_enabled(n) && registers.is_compare(n) {
+                registers.disable_compare_interrupt(n);
+                registers.clear_compare(n);
+                trace!("Alarm {} expired", n);
+                callback();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtc_monotonic_v2::RtcMono;
+    use crate::sim::{SimRtc, SimTimer, Simulated};
+    use crate::timer16_monotonic::NrfMonotonic16;
+    use crate::timer_instance::Instance16;
+    use crate::timer_monotonic::NrfMonotonic;
+
+    type Timer = NrfMonotonic<&'static SimTimer<6>>;
+    type Duration = <Timer as Monotonic>::Duration;
+
+    /// Run until the next interrupt and handle it
+    fn run<M: Monotonic>(mono: &mut M, sim: &impl Simulated) {
+        sim.advance_until_interrupt(u64::MAX);
+        mono.on_interrupt();
+    }
+
+    #[test]
+    fn alarms_fire_in_order() {
+        static FIRST: AtomicU32 = AtomicU32::new(0);
+        static SECOND: AtomicU32 = AtomicU32::new(0);
+        let sim = SimTimer::<6>::new();
+        let (mut mono, [mut first, mut second]) = NrfMonotonic::new(sim).split([
+            || {
+                FIRST.fetch_add(1, Ordering::Relaxed);
+            },
+            || {
+                SECOND.fetch_add(1, Ordering::Relaxed);
+            },
+        ]);
+        unsafe { mono.reset() };
+        assert_eq!((first.channel(), second.channel()), (3, 4));
+
+        let start = mono.now();
+        first.set_at(start + Duration::from_ticks(1000)).unwrap();
+        second.set_at(start + Duration::from_ticks(500)).unwrap();
+        assert!(first.is_pending() && second.is_pending());
+
+        run(&mut mono, sim);
+        assert_eq!(mono.now(), start + Duration::from_ticks(500));
+        assert_eq!(SECOND.load(Ordering::Relaxed), 1);
+        assert_eq!(FIRST.load(Ordering::Relaxed), 0);
+        assert!(first.is_pending() && !second.is_pending());
+
+        run(&mut mono, sim);
+        assert_eq!(mono.now(), start + Duration::from_ticks(1000));
+        assert_eq!(FIRST.load(Ordering::Relaxed), 1);
+        assert!(!first.is_pending());
+
+        // expired alarms stay quiet when their channel matches again
+        sim.advance(3 << 32);
+        mono.on_interrupt();
+        assert_eq!(FIRST.load(Ordering::Relaxed), 1);
+        assert_eq!(SECOND.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn cancelled_alarm_does_not_fire() {
+        static FIRED: AtomicU32 = AtomicU32::new(0);
+        let sim = SimTimer::<6>::new();
+        let (mut mono, [mut alarm]) = NrfMonotonic::new(sim).split([|| {
+            FIRED.fetch_add(1, Ordering::Relaxed);
+        }]);
+        unsafe { mono.reset() };
+
+        alarm.set_at(mono.now() + Duration::from_ticks(10)).unwrap();
+        alarm.cancel();
+        assert!(!alarm.is_pending());
+        sim.advance(100);
+        assert!(!sim.interrupt_pending());
+        mono.on_interrupt();
+        assert_eq!(FIRED.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn alarm_after_many_periods() {
+        static FIRED: AtomicU32 = AtomicU32::new(0);
+        let sim = SimTimer::<6>::new();
+        let (mut mono, [mut alarm]) = NrfMonotonic::new(sim).split([|| {
+            FIRED.fetch_add(1, Ordering::Relaxed);
+        }]);
+        unsafe { mono.reset() };
+
+        for _ in 0..5 {
+            sim.advance(0x7000_0000);
+            mono.on_interrupt();
+        }
+        let at = mono.now() + Duration::from_ticks(0xFFFF_FFFF);
+        alarm.set_at(at).unwrap();
+        while FIRED.load(Ordering::Relaxed) == 0 {
+            run(&mut mono, sim);
+        }
+        assert_eq!(mono.now(), at);
+    }
+
+    #[test]
+    fn alarm_out_of_range() {
+        let sim = SimTimer::<6>::new();
+        let (mut mono, [mut alarm]) = NrfMonotonic::new(sim).split([|| {}]);
+        unsafe { mono.reset() };
+        let now = mono.now();
+        assert_eq!(
+            alarm.set_at(now + Duration::from_ticks(1 << 32)),
+            Err(OutOfRange)
+        );
+        assert!(!alarm.is_pending());
+
+        let sim = SimTimer::<4>::new();
+        let (mut mono, [mut alarm]) = NrfMonotonic16::<_>::new(sim).split([|| {}]);
+        unsafe { mono.reset() };
+        let now = mono.now();
+        assert!(alarm.set_at(now + Duration::from_ticks(0xFFFF)).is_ok());
+        assert_eq!(
+            alarm.set_at(now + Duration::from_ticks(0x1_0000)),
+            Err(OutOfRange)
+        );
+    }
+
+    #[test]
+    fn alarm_in_the_past_fires_immediately() {
+        static FIRED: AtomicU32 = AtomicU32::new(0);
+        let sim = SimTimer::<6>::new();
+        let (mut mono, [mut alarm]) = NrfMonotonic::new(sim).split([|| {
+            FIRED.fetch_add(1, Ordering::Relaxed);
+        }]);
+        unsafe { mono.reset() };
+        sim.advance(1000);
+
+        let now = mono.now();
+        alarm.set_at(now - Duration::from_ticks(100)).unwrap();
+        run(&mut mono, sim);
+        assert_eq!(FIRED.load(Ordering::Relaxed), 1);
+        assert_eq!(mono.now(), now + Duration::from_ticks(1));
+    }
+
+    #[test]
+    fn now_with_half_period_interrupt_between_reads() {
+        let sim = SimTimer::<6>::new();
+        let (mut mono, [alarm]) = NrfMonotonic::<_>::new(sim).split([|| {}]);
+        unsafe { mono.reset() };
+        sim.advance(0x7FFF_FFF0);
+        mono.on_interrupt();
+
+        // the counter is read, then the half-period interrupt runs before the period is read
+        sim.after_next_capture(move || {
+            sim.advance(0x20);
+            sim.shared_period().set(1);
+        });
+        assert_eq!(alarm.now(), 0x7FFF_FFF0);
+        assert_eq!(alarm.now(), 0x8000_0010);
+    }
+
+    #[test]
+    #[should_panic(expected = "not enough free channels")]
+    fn split_more_alarms_than_channels() {
+        let sim = SimTimer::<4>::new();
+        let _ = NrfMonotonic::<_>::new(sim).split([|| {}, || {}]);
+    }
+
+    #[test]
+    fn rtc_alarms() {
+        static FIRED: AtomicU32 = AtomicU32::new(0);
+        type Duration = <RtcMono<&'static SimRtc<4>> as Monotonic>::Duration;
+
+        let sim = SimRtc::<4>::new();
+        let (mut mono, [mut near, mut across]) = RtcMono::new(sim).split([
+            || {
+                FIRED.fetch_add(1, Ordering::Relaxed);
+            },
+            || {
+                FIRED.fetch_add(0x100, Ordering::Relaxed);
+            },
+        ]);
+        unsafe { mono.reset() };
+        assert_eq!((near.channel(), across.channel()), (1, 2));
+
+        sim.advance(0xFF_FF00);
+        let now = mono.now();
+
+        // the RTC needs two ticks to notice a new compare value, plus one for a tick while writing
+        near.set_at(now).unwrap();
+        across.set_at(now + Duration::from_ticks(0x200)).unwrap();
+        run(&mut mono, sim);
+        assert_eq!(FIRED.load(Ordering::Relaxed), 1);
+        assert_eq!(mono.now(), now + Duration::from_ticks(3));
+
+        // the overflow interrupt comes first
+        while FIRED.load(Ordering::Relaxed) == 1 {
+            run(&mut mono, sim);
+        }
+        assert_eq!(FIRED.load(Ordering::Relaxed), 0x101);
+        assert_eq!(mono.now(), now + Duration::from_ticks(0x200));
+    }
+}
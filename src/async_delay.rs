@@ -0,0 +1,239 @@
+//! `embedded-hal-async` delays on an alarm split off a monotonic
+//!
+//! `AsyncDelay` arms its `Alarm` and waits for the compare interrupt instead of spinning, so the
+//! CPU can sleep during driver timeouts. The callback of the alarm has to wake the `AlarmWaker`
+//! of the delay:
+//!
+//! ```ignore
+//! static WAKER: AlarmWaker = AlarmWaker::new();
+//!
+//! let (mono, [alarm]) = NrfMonotonic::new(cx.device.TIMER1).split([|| WAKER.wake()]);
+//! let delay = AsyncDelay::new(alarm, &WAKER);
+//! ```
+//!
+//! Delays longer than half a counter period are waited in several steps.
+
+use crate::alarm::{Alarm, Split};
+use crate::delay::ticks_at_least;
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+use critical_section::Mutex;
+
+/// The waker of a pending `AsyncDelay`, woken from `on_interrupt` of the monotonic
+pub struct AlarmWaker {
+    waker: Mutex<RefCell<Option<Waker>>>,
+}
+
+impl AlarmWaker {
+    pub const fn new() -> Self {
+        Self {
+            waker: Mutex::new(RefCell::new(None)),
+        }
+    }
+
+    /// Wake the waiting task, to be called from the callback of the alarm
+    pub fn wake(&self) {
+        let waker = critical_section::with(|cs| self.waker.borrow_ref_mut(cs).take());
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    fn register(&self, waker: &Waker) {
+        critical_section::with(|cs| {
+            let mut slot = self.waker.borrow_ref_mut(cs);
+            match slot.as_ref() {
+                Some(registered) if registered.will_wake(waker) => {}
+                _ => *slot = Some(waker.clone()),
+            }
+        });
+    }
+}
+
+impl Default for AlarmWaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Async delays on an alarm of the monotonic `M`
+///
+/// Every delay lasts at least the requested time, rounded up to whole ticks plus one tick
+/// because the current tick may be about to end.
+pub struct AsyncDelay<M: Split> {
+    alarm: Alarm<M>,
+    waker: &'static AlarmWaker,
+}
+
+impl<M: Split> AsyncDelay<M> {
+    /// `waker` has to be woken by the callback of `alarm`
+    pub fn new(alarm: Alarm<M>, waker: &'static AlarmWaker) -> Self {
+        Self { alarm, waker }
+    }
+
+    /// Give back the alarm
+    pub fn free(mut self) -> Alarm<M> {
+        self.alarm.cancel();
+        self.alarm
+    }
+
+    async fn wait(&mut self, ticks: u64) {
+        let waker = self.waker;
+        let armed = Armed(&mut self.alarm);
+        let target = armed.0.now() + ticks;
+        let max_step = (1 << M::COUNTER_BITS) >> 1;
+        loop {
+            let now = armed.0.now();
+            if now >= target {
+                return;
+            }
+            // in range, the step is less than a counter period
+            let _ = armed.0.set_at_ticks(target.min(now + max_step));
+
+            let alarm = &*armed.0;
+            poll_fn(|cx| {
+                waker.register(cx.waker());
+                if alarm.is_pending() {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(())
+                }
+            })
+            .await;
+        }
+    }
+}
+
+/// The alarm of a delay future, cancelled when the future is dropped before it completes
+///
+/// The compare interrupt stays disabled then, and the next delay on the alarm does not see the
+/// event of the abandoned one.
+struct Armed<'a, M: Split>(&'a mut Alarm<M>);
+
+impl<M: Split> Drop for Armed<'_, M> {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+impl<M: Split> embedded_hal_async::delay::DelayNs for AsyncDelay<M> {
+    async fn delay_ns(&mut self, ns: u32) {
+        self.wait(ticks_at_least(ns, 1_000_000_000, M::HZ)).await;
+    }
+
+    async fn delay_us(&mut self, us: u32) {
+        self.wait(ticks_at_least(us, 1_000_000, M::HZ)).await;
+    }
+
+    async fn delay_ms(&mut self, ms: u32) {
+        self.wait(ticks_at_least(ms, 1_000, M::HZ)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{SimTimer, Simulated};
+    use crate::{NrfMonotonic, NrfMonotonic16};
+    use core::future::Future;
+    use core::pin::pin;
+    use embedded_hal_async::delay::DelayNs;
+    use rtic_monotonic::Monotonic;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Wake};
+
+    #[derive(Default)]
+    struct CountingWaker(AtomicU32);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Poll `future` to completion, handling the interrupts of `mono` while it is pending
+    ///
+    /// Returns the number of wakes.
+    fn run<M: Monotonic>(
+        mono: &mut M,
+        sim: &impl Simulated,
+        future: impl Future<Output = ()>,
+    ) -> u32 {
+        let counter = Arc::new(CountingWaker::default());
+        let waker = counter.clone().into();
+        let mut cx = Context::from_waker(&waker);
+        let mut future = pin!(future);
+        while future.as_mut().poll(&mut cx).is_pending() {
+            let woken = counter.0.load(Ordering::Relaxed);
+            while counter.0.load(Ordering::Relaxed) == woken {
+                sim.advance_until_interrupt(u64::MAX);
+                mono.on_interrupt();
+            }
+        }
+        counter.0.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn wakes_after_delay() {
+        static WAKER: AlarmWaker = AlarmWaker::new();
+        let sim = SimTimer::<6>::new();
+        let (mut mono, [alarm]) = NrfMonotonic::<_>::new(sim).split([|| WAKER.wake()]);
+        unsafe { mono.reset() };
+        let mut delay = AsyncDelay::new(alarm, &WAKER);
+
+        for (us, ticks) in [(1, 2), (250, 251), (0x1_0000, 0x1_0001)] {
+            let start = mono.now().ticks();
+            let wakes = run(&mut mono, sim, delay.delay_us(us));
+            assert_eq!(mono.now().ticks() - start, ticks);
+            assert_eq!(wakes, 1);
+        }
+        let start = mono.now().ticks();
+        assert_eq!(run(&mut mono, sim, delay.delay_ns(0)), 0);
+        assert_eq!(mono.now().ticks(), start);
+    }
+
+    #[test]
+    fn long_delay_in_steps() {
+        static WAKER: AlarmWaker = AlarmWaker::new();
+        let sim = SimTimer::<4>::new();
+        let (mut mono, [alarm]) = NrfMonotonic16::<_>::new(sim).split([|| WAKER.wake()]);
+        unsafe { mono.reset() };
+        let mut delay = AsyncDelay::new(alarm, &WAKER);
+
+        let start = mono.now().ticks();
+        let wakes = run(&mut mono, sim, delay.delay_ms(1000));
+        assert_eq!(mono.now().ticks() - start, 1_000_001);
+        assert_eq!(wakes, 31);
+    }
+
+    #[test]
+    fn dropped_delay_cancels_the_alarm() {
+        static WAKER: AlarmWaker = AlarmWaker::new();
+        let sim = SimTimer::<6>::new();
+        let (mut mono, [alarm]) = NrfMonotonic::<_>::new(sim).split([|| WAKER.wake()]);
+        unsafe { mono.reset() };
+        let mut delay = AsyncDelay::new(alarm, &WAKER);
+
+        let counter = Arc::new(CountingWaker::default());
+        let waker = counter.clone().into();
+        {
+            let mut future = pin!(delay.delay_us(100));
+            assert!(future
+                .as_mut()
+                .poll(&mut Context::from_waker(&waker))
+                .is_pending());
+        }
+        assert!(!delay.alarm.is_pending());
+
+        // the abandoned compare matches, but neither interrupts nor wakes
+        sim.advance(200);
+        mono.on_interrupt();
+        assert_eq!(counter.0.load(Ordering::Relaxed), 0);
+
+        let start = mono.now().ticks();
+        assert_eq!(run(&mut mono, sim, delay.delay_us(50)), 1);
+        assert_eq!(mono.now().ticks() - start, 51);
+    }
+}
@@ -0,0 +1,306 @@
+//! Drift of the LFCLK, measured against a TIMER on the HFCLK
+//!
+//! With the LFCLK on the internal RC oscillator, an RTC runs up to ±500ppm off. `Calibration`
+//! measures this error against a 16MHz TIMER, which is as accurate as the HFXO it runs from. A
+//! PPI channel routes every RTC tick to a capture of the TIMER and, through its fork, to the
+//! TIMER's START task, so the TIMER starts at an RTC tick and the count of the latest tick can
+//! be read together with the time of the `RtcMono`:
+//!
+//! ```ignore
+//! let rtc = RtcMono::new(cx.device.RTC1);
+//! let mut calibration = Calibration::new(rtc.clock(), cx.device.TIMER2, &cx.device.PPI, 2);
+//!
+//! calibration.start();
+//! // a second later, from a task
+//! let drift = calibration.finish().unwrap();
+//! sample::spawn_after(drift.to_rtc(10u64.secs())).ok();
+//! ```
+//!
+//! A measurement resolves one TIMER tick, 1/16µs, so a one-second window gives the drift to
+//! 0.1ppm. The HFCLK runs from `start` to `finish` and has to run from the crystal.
+//!
+//! The drift is only measured: `RtcMono`, its alarms and its instants keep running on the
+//! uncorrected RTC. Durations can be converted with the `Drift`, and since an RC oscillator
+//! follows the temperature, the measurement has to be repeated, e.g. from a periodic task.
+
+use crate::clocks::{check_hfxo, ClockError, ClockRegisters};
+use crate::ppi::PpiRegisters;
+use crate::rtc_instance::{RtcInstance, RtcRegisters};
+use crate::rtc_monotonic_v2::{RtcClock, RTC_HZ};
+use crate::timer_instance::{prescaler, Bitmode, Instance32, TimerRegisters, TIMER_BASE_HZ};
+
+use core::convert::TryFrom;
+
+type RtcDuration = fugit::TimerDurationU64<RTC_HZ>;
+
+/// Frequency error of the LFCLK, positive if the RTC runs fast
+///
+/// Converts durations between true time and ticks of the drifting RTC:
+///
+/// ```
+/// use fugit::{ExtU64, TimerDurationU64};
+/// use nrf_monotonic::Drift;
+///
+/// // the RTC runs 500ppm fast
+/// let drift = Drift::from_ppb(500_000);
+/// assert_eq!(drift.to_rtc(1u64.secs()).ticks(), 32_784);
+/// let measured = TimerDurationU64::<32_768>::from_ticks(32_784);
+/// assert_eq!(drift.to_true(measured).to_secs(), 1);
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct Drift {
+    ppb: i32,
+}
+
+impl Drift {
+    const BILLION: i128 = 1_000_000_000;
+
+    pub const fn from_ppb(ppb: i32) -> Self {
+        Self { ppb }
+    }
+
+    /// The error in parts per billion
+    pub const fn ppb(&self) -> i32 {
+        self.ppb
+    }
+
+    /// The error in parts per million, rounded towards zero
+    pub const fn ppm(&self) -> i32 {
+        self.ppb / 1000
+    }
+
+    /// The RTC ticks that pass in `duration` of true time, e.g. to schedule a task after it
+    pub fn to_rtc(&self, duration: RtcDuration) -> RtcDuration {
+        let ticks = duration.ticks() as i128;
+        let ticks = ticks + div_round(ticks * self.ppb as i128, Self::BILLION);
+        RtcDuration::from_ticks(ticks.max(0) as u64)
+    }
+
+    /// The true time that passed in `duration` measured by the RTC, e.g. between two instants
+    pub fn to_true(&self, duration: RtcDuration) -> RtcDuration {
+        let ticks = duration.ticks() as i128 * Self::BILLION;
+        let ticks = div_round(ticks, Self::BILLION + self.ppb as i128);
+        RtcDuration::from_ticks(ticks.max(0) as u64)
+    }
+}
+
+/// `n / d` rounded to the nearest integer, `d` is positive
+fn div_round(n: i128, d: i128) -> i128 {
+    (n + n.signum() * d / 2) / d
+}
+
+/// Measures the drift of the RTC of an `RtcMono` against a TIMER
+///
+/// The TIMER is owned for the measurements, the RTC keeps running as the monotonic.
+pub struct Calibration<RTC: RtcInstance, TIM: Instance32> {
+    clock: RtcClock<RTC>,
+    timer: TIM,
+    /// RTC time of the tick that started the TIMER
+    started_at: Option<u64>,
+}
+
+impl<RTC: RtcInstance, TIM: Instance32> Calibration<RTC, TIM> {
+    const CC_TICK: usize = 0;
+    /// The TIMER counts 2^32 ticks in 268s, the window has to be shorter
+    const MAX_WINDOW: u64 = 256 * RTC_HZ as u64;
+
+    /// Stop the TIMER and connect it to the RTC of `clock` through the PPI channel `channel`
+    ///
+    /// The PPI channel must not be used for anything else.
+    pub fn new<PPI: PpiRegisters>(
+        clock: RtcClock<RTC>,
+        timer: TIM,
+        ppi: &PPI,
+        channel: usize,
+    ) -> Self {
+        let t0 = timer.as_timer0();
+        t0.task_stop();
+        t0.configure(Bitmode::Bits32, prescaler(TIMER_BASE_HZ));
+        t0.task_clear();
+
+        // the TICK event is only routed while measuring
+        ppi.connect(
+            channel,
+            clock.registers().tick_event_address(),
+            t0.capture_task_address(Self::CC_TICK),
+            t0.start_task_address(),
+        );
+        ppi.enable(channel);
+
+        Self {
+            clock,
+            timer,
+            started_at: None,
+        }
+    }
+
+    /// `new`, after checking that the HFCLK, the reference of the measurement, runs from the
+    /// crystal oscillator
+    ///
+    /// `clocks` is the CLOCK peripheral or the `Clocks` of the HAL.
+    pub fn try_new<PPI: PpiRegisters>(
+        clock: RtcClock<RTC>,
+        timer: TIM,
+        ppi: &PPI,
+        channel: usize,
+        clocks: &impl ClockRegisters,
+    ) -> Result<Self, ClockError> {
+        check_hfxo(clocks)?;
+        Ok(Self::new(clock, timer, ppi, channel))
+    }
+
+    /// Start a measurement, the TIMER starts at the next RTC tick
+    pub fn start(&mut self) {
+        let (r, t0) = (self.clock.registers(), self.timer.as_timer0());
+        loop {
+            t0.task_stop();
+            t0.task_clear();
+
+            let before = self.clock.ticks();
+            r.enable_tick_event();
+            if self.clock.ticks() == before {
+                self.started_at = Some(before + 1);
+                return;
+            }
+            // a tick came in between, it may or may not have started the TIMER
+            r.disable_tick_event();
+        }
+    }
+
+    /// Stop the measurement and return the drift since `start`
+    ///
+    /// `None` if no measurement was started, the RTC did not tick since, or the window is
+    /// longer than 256 seconds.
+    pub fn finish(&mut self) -> Option<Drift> {
+        let started_at = self.started_at.take()?;
+        let (r, t0) = (self.clock.registers(), self.timer.as_timer0());
+
+        // the count and the time of the same tick, unless the next one came in between
+        let (ticks, count) = loop {
+            let ticks = self.clock.ticks();
+            let count = t0.read_cc(Self::CC_TICK);
+            if self.clock.ticks() == ticks {
+                break (ticks, count);
+            }
+        };
+        r.disable_tick_event();
+        t0.task_stop();
+
+        let window = ticks.checked_sub(started_at).filter(|&w| w > 0)?;
+        if window >= Self::MAX_WINDOW || count == 0 {
+            return None;
+        }
+
+        // the TIMER counts TIMER_BASE_HZ / RTC_HZ times per tick of an exact RTC
+        let (window, count) = (window as i128, count as i128);
+        let expected = window * TIMER_BASE_HZ as i128;
+        let measured = count * RTC_HZ as i128;
+        let ppb = div_round((expected - measured) * Drift::BILLION, measured);
+        i32::try_from(ppb).ok().map(Drift::from_ppb)
+    }
+
+    /// Release the TIMER, the PPI channel stays connected
+    pub fn free(self) -> TIM {
+        self.clock.registers().disable_tick_event();
+        self.timer.as_timer0().task_stop();
+        self.timer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtc_monotonic_v2::RtcMono;
+    use crate::sim::{SimClock, SimHybrid, SimRtc, SimTimer, Simulated};
+    use rtic_monotonic::Monotonic;
+
+    type Rtc = RtcMono<&'static SimRtc<4>>;
+    type Cal = Calibration<&'static SimRtc<4>, &'static SimTimer<4>>;
+
+    fn setup(rtc_tick: u64) -> (&'static SimHybrid, Rtc, Cal) {
+        let sim = SimHybrid::new();
+        sim.set_rtc_tick(rtc_tick);
+        let mut rtc = RtcMono::new(sim.rtc);
+        unsafe { rtc.reset() };
+        let calibration = Calibration::new(rtc.clock(), sim.timer, sim.ppi, 0);
+        (sim, rtc, calibration)
+    }
+
+    /// Measure over `ticks` RTC ticks
+    fn measure(rtc_tick: u64, ticks: u64) -> Option<Drift> {
+        let (sim, _rtc, mut calibration) = setup(rtc_tick);
+        sim.advance(10 * rtc_tick);
+        calibration.start();
+        sim.advance(ticks * rtc_tick + rtc_tick / 2);
+        calibration.finish()
+    }
+
+    #[test]
+    fn exact_rtc_has_no_drift() {
+        let drift = measure(SimHybrid::RTC_TICK, 2048).unwrap();
+        assert!(drift.ppb().abs() < 1000, "{:?}", drift);
+    }
+
+    #[test]
+    fn fast_and_slow_rtc() {
+        // 15625 / 15617 - 1 = 512.3ppm fast, 15625 / 15633 - 1 = 511.7ppm slow, the window
+        // resolves 1ppm
+        let fast = measure(SimHybrid::RTC_TICK - 8, 2048).unwrap();
+        assert!((fast.ppb() - 512_263).abs() < 1000, "{:?}", fast);
+        let slow = measure(SimHybrid::RTC_TICK + 8, 2048).unwrap();
+        assert!((slow.ppb() + 511_738).abs() < 1000, "{:?}", slow);
+    }
+
+    #[test]
+    fn timer_only_runs_while_measuring() {
+        let (sim, _rtc, mut calibration) = setup(SimHybrid::RTC_TICK);
+        assert!(!sim.timer.is_running());
+        calibration.start();
+        sim.advance(SimHybrid::RTC_TICK);
+        assert!(sim.timer.is_running());
+        calibration.finish();
+        assert!(!sim.timer.is_running());
+
+        sim.advance(10 * SimHybrid::RTC_TICK);
+        assert!(!sim.timer.is_running());
+    }
+
+    #[test]
+    fn finish_needs_a_window() {
+        let (_sim, _rtc, mut calibration) = setup(SimHybrid::RTC_TICK);
+        assert_eq!(calibration.finish(), None);
+        calibration.start();
+        assert_eq!(calibration.finish(), None);
+    }
+
+    #[test]
+    fn durations_follow_the_drift() {
+        let fast = Drift::from_ppb(500_000);
+        assert_eq!(fast.to_rtc(RtcDuration::from_ticks(32_768)).ticks(), 32_784);
+        assert_eq!(
+            fast.to_true(RtcDuration::from_ticks(32_784)).ticks(),
+            32_768
+        );
+
+        let slow = Drift::from_ppb(-500_000);
+        assert_eq!(slow.to_rtc(RtcDuration::from_ticks(32_768)).ticks(), 32_752);
+        assert_eq!(
+            slow.to_true(RtcDuration::from_ticks(32_752)).ticks(),
+            32_768
+        );
+    }
+
+    #[test]
+    fn try_new_needs_the_hfxo() {
+        let sim = SimHybrid::new();
+        let rtc = RtcMono::new(sim.rtc);
+        let clocks = SimClock::default();
+        clocks.lfclk.set(true);
+        let calibration = Cal::try_new(rtc.clock(), sim.timer, sim.ppi, 0, &clocks);
+        assert_eq!(calibration.err(), Some(ClockError::HfxoNotRunning));
+
+        clocks.hfxo.set(true);
+        assert!(Cal::try_new(rtc.clock(), sim.timer, sim.ppi, 0, &clocks).is_ok());
+    }
+}
@@ -0,0 +1,99 @@
+//! Clock sources the monotonics depend on
+//!
+//! A TIMER counts the HFCLK, which is only accurate while it runs from the crystal oscillator
+//! (HFXO), and an RTC does not count at all while the LFCLK is stopped. The `try_new`
+//! constructors read the clock status first, so a missing `enable_ext_hfosc()` or
+//! `start_lfclk()` is reported at startup instead of showing up as a drifting or frozen time.
+
+use crate::hal;
+
+#[cfg(not(any(feature = "5340-app", feature = "5340-net", feature = "9160")))]
+use hal::pac::{clock, CLOCK};
+#[cfg(any(feature = "5340-app", feature = "5340-net", feature = "9160"))]
+use hal::pac::{clock_ns as clock, CLOCK_NS as CLOCK};
+
+/// SRC bit of HFCLKSTAT, set while the HFCLK runs from the HFXO
+const HFCLKSTAT_SRC_XTAL: u32 = 1;
+/// STATE bit of HFCLKSTAT and LFCLKSTAT, set while the clock is running
+const CLKSTAT_STATE_RUNNING: u32 = 1 << 16;
+
+/// A clock a monotonic needs was not running when it was created
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum ClockError {
+    /// The HFCLK is stopped or runs from the internal RC oscillator
+    HfxoNotRunning,
+    /// The LFCLK is stopped
+    LfclkNotRunning,
+}
+
+/// Status of the HFCLK and LFCLK
+///
+/// Implemented for the PAC peripheral, for the `Clocks` of the HAL, which owns it, and by a
+/// simulated clock to test the checks on the host.
+pub trait ClockRegisters {
+    /// The HFCLK runs from the crystal oscillator
+    fn is_hfxo_running(&self) -> bool;
+    /// The LFCLK runs, from any source
+    fn is_lfclk_running(&self) -> bool;
+}
+
+impl ClockRegisters for clock::RegisterBlock {
+    #[inline(always)]
+    fn is_hfxo_running(&self) -> bool {
+        let running = HFCLKSTAT_SRC_XTAL | CLKSTAT_STATE_RUNNING;
+        self.hfclkstat.read().bits() & running == running
+    }
+
+    #[inline(always)]
+    fn is_lfclk_running(&self) -> bool {
+        self.lfclkstat.read().bits() & CLKSTAT_STATE_RUNNING != 0
+    }
+}
+
+impl ClockRegisters for CLOCK {
+    #[inline(always)]
+    fn is_hfxo_running(&self) -> bool {
+        (**self).is_hfxo_running()
+    }
+
+    #[inline(always)]
+    fn is_lfclk_running(&self) -> bool {
+        (**self).is_lfclk_running()
+    }
+}
+
+// There is no published HAL, and so no `Clocks`, for the nRF52820: `try_new` takes the PAC
+// `CLOCK` there
+#[cfg(not(feature = "52820"))]
+impl<H, L, LSTAT> ClockRegisters for hal::clocks::Clocks<H, L, LSTAT> {
+    #[inline(always)]
+    fn is_hfxo_running(&self) -> bool {
+        // SAFETY: `Clocks` owns the peripheral, the status is only read
+        unsafe { &*CLOCK::ptr() }.is_hfxo_running()
+    }
+
+    #[inline(always)]
+    fn is_lfclk_running(&self) -> bool {
+        // SAFETY: `Clocks` owns the peripheral, the status is only read
+        unsafe { &*CLOCK::ptr() }.is_lfclk_running()
+    }
+}
+
+/// `Ok` if the HFCLK runs from the crystal oscillator
+pub(crate) fn check_hfxo(clock: &impl ClockRegisters) -> Result<(), ClockError> {
+    if clock.is_hfxo_running() {
+        Ok(())
+    } else {
+        Err(ClockError::HfxoNotRunning)
+    }
+}
+
+/// `Ok` if the LFCLK runs
+pub(crate) fn check_lfclk(clock: &impl ClockRegisters) -> Result<(), ClockError> {
+    if clock.is_lfclk_running() {
+        Ok(())
+    } else {
+        Err(ClockError::LfclkNotRunning)
+    }
+}
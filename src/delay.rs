@@ -0,0 +1,165 @@
+//! Blocking delays on a borrowed monotonic
+//!
+//! `Delay` busy-waits on `now()` of a monotonic that is already running, so short delays in
+//! `#[init]` do not need a TIMER of their own. The counter of `NrfMonotonic` and `NrfMonotonic16`
+//! runs from `new` on, before RTIC calls `reset` and handles the interrupt. `MonoTimer` and
+//! `RtcMono` only count after `reset`, a delay on them before blocks forever. Any monotonic implementing `Split` works: only the bits
+//! of the hardware counter are relied on, longer delays are waited in chunks of half a counter
+//! period.
+
+use crate::alarm::Split;
+
+/// Blocking delays busy-waiting on the monotonic `M`
+///
+/// Every delay lasts at least the requested time, rounded up to whole ticks plus one tick
+/// because the current tick may be about to end.
+pub struct Delay<'a, M: Split> {
+    mono: &'a mut M,
+}
+
+impl<'a, M: Split> Delay<'a, M> {
+    pub fn new(mono: &'a mut M) -> Self {
+        Self { mono }
+    }
+
+    /// Busy-wait for `ns` nanoseconds
+    pub fn wait_ns(&mut self, ns: u32) {
+        self.wait(ticks_at_least(ns, 1_000_000_000, M::HZ));
+    }
+
+    /// Busy-wait for `us` microseconds
+    pub fn wait_us(&mut self, us: u32) {
+        self.wait(ticks_at_least(us, 1_000_000, M::HZ));
+    }
+
+    /// Busy-wait for `ms` milliseconds
+    pub fn wait_ms(&mut self, ms: u32) {
+        self.wait(ticks_at_least(ms, 1_000, M::HZ));
+    }
+
+    fn wait(&mut self, ticks: u64) {
+        let mask = (1 << M::COUNTER_BITS) - 1;
+        let mut remaining = ticks;
+        while remaining > 0 {
+            let chunk = remaining.min(mask >> 1);
+            let start = M::ticks(self.mono.now());
+            while M::ticks(self.mono.now()).wrapping_sub(start) & mask < chunk {}
+            remaining -= chunk;
+        }
+    }
+}
+
+/// Ticks at `hz` that last at least `amount` in units of 1 / `unit_hz` seconds
+///
+/// One tick is added because the current tick may be about to end.
+pub(crate) fn ticks_at_least(amount: u32, unit_hz: u64, hz: u32) -> u64 {
+    match amount {
+        0 => 0,
+        _ => (amount as u64 * hz as u64).div_ceil(unit_hz) + 1,
+    }
+}
+
+impl<M: Split> embedded_hal::delay::DelayNs for Delay<'_, M> {
+    fn delay_ns(&mut self, ns: u32) {
+        self.wait_ns(ns);
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        self.wait_us(us);
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        self.wait_ms(ms);
+    }
+}
+
+macro_rules! impl_delay_02 {
+    ($($t:ty),*) => {
+        $(
+            impl<M: Split> embedded_hal_02::blocking::delay::DelayUs<$t> for Delay<'_, M> {
+                fn delay_us(&mut self, us: $t) {
+                    self.wait_us(us as u32);
+                }
+            }
+
+            impl<M: Split> embedded_hal_02::blocking::delay::DelayMs<$t> for Delay<'_, M> {
+                fn delay_ms(&mut self, ms: $t) {
+                    self.wait_ms(ms as u32);
+                }
+            }
+        )*
+    };
+}
+
+impl_delay_02!(u8, u16, u32);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimTimer;
+    use crate::since_epoch_monotonic::MonoTimer;
+    use crate::timer16_monotonic::NrfMonotonic16;
+    use crate::timer_monotonic::NrfMonotonic;
+    use embedded_hal::delay::DelayNs;
+    use embedded_hal_02::blocking::delay::{DelayMs, DelayUs};
+    use rtic_monotonic::Monotonic;
+
+    /// Check that `f` waited for `ticks`, every capture advancing the counter by `step`
+    fn assert_waits(sim: &SimTimer<4>, ticks: u64, step: u64, f: impl FnOnce()) {
+        let start = sim.total();
+        f();
+        let elapsed = sim.total() - start;
+        // every chunk starts with a capture and can overshoot by one
+        assert!(
+            elapsed >= ticks && elapsed < ticks + 2 * step * (1 + ticks / 0x7FFF),
+            "waited {} instead of {} ticks",
+            elapsed,
+            ticks
+        );
+    }
+
+    #[test]
+    fn delays_last_at_least_the_requested_time() {
+        let sim = SimTimer::<4>::new();
+        let mut mono = NrfMonotonic::<_>::new(sim);
+        let mut delay = Delay::new(&mut mono);
+        sim.set_advance_on_capture(3);
+
+        assert_waits(sim, 251, 3, || DelayUs::delay_us(&mut delay, 250u16));
+        assert_waits(sim, 2001, 3, || DelayMs::delay_ms(&mut delay, 2u8));
+        assert_waits(sim, 3, 3, || DelayNs::delay_ns(&mut delay, 1500));
+        assert_waits(sim, 0, 3, || DelayNs::delay_ns(&mut delay, 0));
+    }
+
+    #[test]
+    fn delays_follow_hz() {
+        let sim = SimTimer::<4>::new();
+        let mut mono = NrfMonotonic::<_, 16_000_000>::new(sim);
+        let mut delay = Delay::new(&mut mono);
+        sim.set_advance_on_capture(1);
+
+        assert_waits(sim, 16_001, 1, || DelayNs::delay_us(&mut delay, 1000));
+        assert_waits(sim, 2, 1, || DelayNs::delay_ns(&mut delay, 1));
+    }
+
+    #[test]
+    fn long_delay_on_16_bit_counter() {
+        let sim = SimTimer::<4>::new();
+        let mut mono = NrfMonotonic16::<_>::new(sim);
+        sim.set_advance_on_capture(0x1000);
+
+        // no interrupt is handled, only the 16-bit counter tracks the time
+        assert_waits(sim, 1_000_001, 0x1000, || {
+            DelayMs::delay_ms(&mut Delay::new(&mut mono), 1000u32)
+        });
+    }
+
+    #[test]
+    fn delay_on_mono_timer() {
+        let sim = SimTimer::<4>::new();
+        let mut mono = MonoTimer::new(sim);
+        unsafe { mono.reset() };
+        sim.set_advance_on_capture(1);
+        assert_waits(sim, 11, 1, || Delay::new(&mut mono).wait_us(10));
+    }
+}
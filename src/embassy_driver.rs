@@ -0,0 +1,407 @@
+//! `embassy-time` driver on top of the monotonics of this crate
+//!
+//! The driver keeps the monotonic and a queue of wakers in critical section mutexes.
+//! `schedule_wake` arms the compare channel of the monotonic for the earliest waker, the TIMER or
+//! RTC interrupt has to call `on_interrupt`. Wakers are woken, one at a time, after the queue is
+//! released, so a waker may schedule again.
+
+use core::cell::{Cell, RefCell};
+use core::task::Waker;
+use critical_section::Mutex;
+use embassy_time_driver::{Driver, TICK_HZ};
+use fugit::TimerInstantU64;
+use rtic_monotonic::Monotonic;
+
+/// `embassy_time_driver::Driver` for a monotonic of this crate, with room for `N` timers
+///
+/// The monotonic's `HZ` has to match the `tick-hz-*` feature of embassy-time, e.g.
+/// `tick-hz-32_768` for `RtcMono`. A critical section implementation is required, on a single
+/// core chip the `critical-section-single-core` feature of `cortex-m` provides one.
+///
+/// The driver does not know the interrupt of the monotonic, it has to be unmasked in the NVIC
+/// after `init`:
+///
+/// ```ignore
+/// embassy_time_driver::time_driver_impl!(
+///     static DRIVER: EmbassyDriver<RtcMono<RTC1>> = EmbassyDriver::new()
+/// );
+///
+/// #[interrupt]
+/// fn RTC1() {
+///     DRIVER.on_interrupt();
+/// }
+///
+/// DRIVER.init(RtcMono::new(p.RTC1));
+/// unsafe { NVIC::unmask(Interrupt::RTC1) };
+/// ```
+///
+/// When all `N` timers are taken, scheduling another one wakes the earliest timer early, its
+/// task schedules it again when polled.
+pub struct EmbassyDriver<M, const N: usize = 16> {
+    mono: Mutex<RefCell<Option<M>>>,
+    queue: Mutex<RefCell<Queue<N>>>,
+    /// Time of the last read, returned while `on_interrupt` holds the monotonic
+    last_now: Mutex<Cell<u64>>,
+}
+
+/// A waker and the tick it has to be woken at
+struct Timer {
+    at: u64,
+    waker: Waker,
+}
+
+/// The wakers waiting for their deadline
+struct Queue<const N: usize> {
+    timers: [Option<Timer>; N],
+}
+
+impl<const N: usize> Queue<N> {
+    const EMPTY: Option<Timer> = None;
+
+    const fn new() -> Self {
+        Self {
+            timers: [Self::EMPTY; N],
+        }
+    }
+
+    /// Add `waker` or move its deadline forward to `at`
+    ///
+    /// Returns the waker of the earliest timer if the queue was full, to be woken early.
+    fn schedule_wake(&mut self, at: u64, waker: &Waker) -> Option<Waker> {
+        if let Some(timer) = self
+            .timers
+            .iter_mut()
+            .flatten()
+            .find(|timer| timer.waker.will_wake(waker))
+        {
+            timer.at = timer.at.min(at);
+            return None;
+        }
+        let timer = Timer {
+            at,
+            waker: waker.clone(),
+        };
+        match self.timers.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(timer);
+                None
+            }
+            None => match self.earliest() {
+                Some(earliest) => self.timers[earliest]
+                    .replace(timer)
+                    .map(|timer| timer.waker),
+                None => Some(timer.waker),
+            },
+        }
+    }
+
+    /// Remove the waker of a timer that expired at `now`
+    fn pop_expired(&mut self, now: u64) -> Option<Waker> {
+        let earliest = self.earliest()?;
+        match &self.timers[earliest] {
+            Some(timer) if timer.at <= now => self.timers[earliest].take().map(|timer| timer.waker),
+            _ => None,
+        }
+    }
+
+    /// The earliest deadline, `u64::MAX` if the queue is empty
+    fn next_expiration(&self) -> u64 {
+        self.timers
+            .iter()
+            .flatten()
+            .map(|timer| timer.at)
+            .min()
+            .unwrap_or(u64::MAX)
+    }
+
+    /// Index of the timer with the earliest deadline
+    fn earliest(&self) -> Option<usize> {
+        (0..N)
+            .filter(|&i| self.timers[i].is_some())
+            .min_by_key(|&i| self.timers[i].as_ref().map(|timer| timer.at))
+    }
+}
+
+impl<M, const N: usize> EmbassyDriver<M, N> {
+    pub const fn new() -> Self {
+        Self {
+            mono: Mutex::new(RefCell::new(None)),
+            queue: Mutex::new(RefCell::new(Queue::new())),
+            last_now: Mutex::new(Cell::new(0)),
+        }
+    }
+}
+
+impl<M, const N: usize> Default for EmbassyDriver<M, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M, const HZ: u32, const N: usize> EmbassyDriver<M, N>
+where
+    M: Monotonic<Instant = TimerInstantU64<HZ>>,
+{
+    /// Fails to compile if the monotonic does not tick at the rate selected for embassy-time
+    const TICK_RATE: () = assert!(
+        HZ as u64 == TICK_HZ,
+        "HZ of the monotonic must match the tick-hz feature of embassy-time"
+    );
+
+    /// Start `mono` and use it as time base
+    ///
+    /// The interrupt of the TIMER or RTC is not unmasked.
+    pub fn init(&self, mut mono: M) {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::TICK_RATE;
+
+        critical_section::with(|cs| {
+            unsafe { mono.reset() };
+            self.mono.borrow_ref_mut(cs).replace(mono);
+        });
+        self.arm();
+    }
+
+    /// Handle the interrupt of the TIMER or RTC and wake the expired timers
+    ///
+    /// The callbacks of alarms split off the monotonic run while it is borrowed, `now` returns
+    /// the time the interrupt was entered at then and `schedule_wake` leaves arming to this
+    /// function.
+    pub fn on_interrupt(&self) {
+        critical_section::with(|cs| {
+            if let Some(mono) = self.mono.borrow_ref_mut(cs).as_mut() {
+                self.last_now.borrow(cs).set(mono.now().ticks());
+                mono.clear_compare_flag();
+                mono.on_interrupt();
+            }
+        });
+        self.arm();
+    }
+
+    /// Current time in ticks, 0 before `init`
+    pub fn now(&self) -> u64 {
+        critical_section::with(|cs| {
+            let last_now = self.last_now.borrow(cs);
+            if let Ok(mut mono) = self.mono.borrow(cs).try_borrow_mut() {
+                if let Some(mono) = mono.as_mut() {
+                    last_now.set(mono.now().ticks());
+                }
+            }
+            last_now.get()
+        })
+    }
+
+    /// Wake `waker` at `at`, immediately if `at` has already passed
+    pub fn schedule_wake(&self, at: u64, waker: &Waker) {
+        let evicted =
+            critical_section::with(|cs| self.queue.borrow_ref_mut(cs).schedule_wake(at, waker));
+        if let Some(waker) = evicted {
+            waker.wake();
+        }
+        self.arm();
+    }
+
+    /// Set the compare value to the earliest deadline in the queue, waking expired timers
+    ///
+    /// Every waker is woken after the queue and the monotonic are released.
+    fn arm(&self) {
+        loop {
+            let expired = critical_section::with(|cs| {
+                // while `on_interrupt` holds the monotonic, it arms after
+                let mut mono = self.mono.borrow(cs).try_borrow_mut().ok()?;
+                let mono = mono.as_mut()?;
+                let mut queue = self.queue.borrow_ref_mut(cs);
+                loop {
+                    let now = mono.now().ticks();
+                    if let Some(waker) = queue.pop_expired(now) {
+                        return Some(waker);
+                    }
+                    let next = queue.next_expiration();
+                    if next == u64::MAX {
+                        return None;
+                    }
+                    mono.set_compare(TimerInstantU64::from_ticks(next));
+                    // the deadline may have passed while the compare value was written
+                    if mono.now().ticks() < next {
+                        return None;
+                    }
+                }
+            });
+            match expired {
+                Some(waker) => waker.wake(),
+                None => return,
+            }
+        }
+    }
+}
+
+impl<M, const HZ: u32, const N: usize> Driver for EmbassyDriver<M, N>
+where
+    M: Monotonic<Instant = TimerInstantU64<HZ>> + Send + 'static,
+{
+    fn now(&self) -> u64 {
+        EmbassyDriver::now(self)
+    }
+
+    fn schedule_wake(&self, at: u64, waker: &Waker) {
+        EmbassyDriver::schedule_wake(self, at, waker)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{SimTimer, Simulated};
+    use crate::NrfMonotonic;
+    use std::boxed::Box;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    type Mono = NrfMonotonic<&'static SimTimer<4>>;
+
+    thread_local! {
+        /// Called by every wake and by the alarm callback `run_hook`
+        static HOOK: RefCell<Option<Box<dyn Fn()>>> = RefCell::new(None);
+    }
+
+    fn run_hook() {
+        HOOK.with(|hook| {
+            if let Some(hook) = &*hook.borrow() {
+                hook()
+            }
+        });
+    }
+
+    #[derive(Default)]
+    struct CountingWaker(AtomicU32);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            run_hook();
+        }
+    }
+
+    /// A driver that lives as long as the hooks referring to it
+    fn leak_driver<const N: usize>() -> &'static EmbassyDriver<Mono, N> {
+        Box::leak(Box::new(EmbassyDriver::new()))
+    }
+
+    #[test]
+    fn wakes_at_deadline() {
+        let sim = SimTimer::<4>::new();
+        let driver = EmbassyDriver::<_>::new();
+        driver.init(NrfMonotonic::<_>::new(sim));
+
+        let first = Arc::new(CountingWaker::default());
+        let second = Arc::new(CountingWaker::default());
+        sim.advance(1000);
+        driver.schedule_wake(0x1_2345_6789, &first.clone().into());
+        driver.schedule_wake(5000, &second.clone().into());
+
+        let woken = |waker: &Arc<CountingWaker>| waker.0.load(Ordering::Relaxed) == 1;
+        while !woken(&second) {
+            sim.advance_until_interrupt(u64::MAX);
+            driver.on_interrupt();
+        }
+        assert_eq!(driver.now(), 5000);
+        assert!(!woken(&first));
+
+        while !woken(&first) {
+            sim.advance_until_interrupt(u64::MAX);
+            driver.on_interrupt();
+        }
+        assert_eq!(driver.now(), 0x1_2345_6789);
+    }
+
+    #[test]
+    fn past_deadline_wakes_immediately() {
+        let sim = SimTimer::<4>::new();
+        let driver = EmbassyDriver::<_>::new();
+        driver.init(NrfMonotonic::<_>::new(sim));
+        sim.advance(1000);
+
+        let waker = Arc::new(CountingWaker::default());
+        driver.schedule_wake(10, &waker.clone().into());
+        assert_eq!(waker.0.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn now_before_init() {
+        let driver = EmbassyDriver::<Mono>::new();
+        assert_eq!(driver.now(), 0);
+    }
+
+    #[test]
+    fn waker_schedules_again() {
+        let sim = SimTimer::<4>::new();
+        let driver = leak_driver::<16>();
+        driver.init(NrfMonotonic::<_>::new(sim));
+
+        let counter = Arc::new(CountingWaker::default());
+        let waker: Waker = counter.clone().into();
+        let (again, woken) = (waker.clone(), counter.clone());
+        HOOK.with(|hook| {
+            hook.replace(Some(Box::new(move || {
+                if woken.0.load(Ordering::Relaxed) < 3 {
+                    driver.schedule_wake(driver.now() + 1000, &again);
+                }
+            })))
+        });
+
+        driver.schedule_wake(1000, &waker);
+        while counter.0.load(Ordering::Relaxed) < 3 {
+            sim.advance_until_interrupt(u64::MAX);
+            driver.on_interrupt();
+        }
+        assert_eq!(driver.now(), 3000);
+    }
+
+    #[test]
+    fn alarm_callback_uses_the_driver() {
+        let sim = SimTimer::<6>::new();
+        let driver: &'static EmbassyDriver<NrfMonotonic<&'static SimTimer<6>>> =
+            Box::leak(Box::new(EmbassyDriver::new()));
+        let (mono, [mut alarm]) = NrfMonotonic::<_>::new(sim).split([run_hook]);
+        driver.init(mono);
+
+        let counter = Arc::new(CountingWaker::default());
+        let waker: Waker = counter.clone().into();
+        let called_at = Arc::new(AtomicU32::new(0));
+        let at = called_at.clone();
+        HOOK.with(|hook| {
+            hook.replace(Some(Box::new(move || {
+                if at.load(Ordering::Relaxed) == 0 {
+                    let now = driver.now();
+                    at.store(now as u32, Ordering::Relaxed);
+                    driver.schedule_wake(now + 500, &waker);
+                }
+            })))
+        });
+
+        alarm.set_at(TimerInstantU64::from_ticks(2000)).unwrap();
+        while counter.0.load(Ordering::Relaxed) == 0 {
+            sim.advance_until_interrupt(u64::MAX);
+            driver.on_interrupt();
+        }
+        assert_eq!(called_at.load(Ordering::Relaxed), 2000);
+        assert_eq!(driver.now(), 2500);
+    }
+
+    #[test]
+    fn full_queue_wakes_the_earliest() {
+        let sim = SimTimer::<4>::new();
+        let driver = EmbassyDriver::<_, 2>::new();
+        driver.init(NrfMonotonic::<_>::new(sim));
+
+        let wakers: [Arc<CountingWaker>; 3] = Default::default();
+        for (waker, at) in wakers.iter().zip([5000, 3000, 4000]) {
+            driver.schedule_wake(at, &waker.clone().into());
+        }
+        let woken = wakers
+            .each_ref()
+            .map(|waker| waker.0.load(Ordering::Relaxed));
+        assert_eq!(woken, [0, 1, 0]);
+    }
+}
@@ -1,6 +1,9 @@
 #![macro_use]
 
 // stole this from embassy-rs
+//
+// The internal trace points go to `log` or defmt, whichever backend is enabled. Without a
+// backend the arguments are still evaluated once, so both builds behave the same.
 
 macro_rules! trace {
     ($s:literal $(, $x:expr)* $(,)?) => {
@@ -9,7 +12,7 @@ macro_rules! trace {
             ::log::trace!($s $(, $x)*);
             #[cfg(feature = "defmt-impl")]
             ::defmt::trace!($s $(, $x)*);
-            #[cfg(not(any(feature = "log", feature="defmt")))]
+            #[cfg(not(any(feature = "log", feature = "defmt-impl")))]
             let _ = ($( & $x ),*);
         }
     };
@@ -22,7 +25,7 @@ macro_rules! debug {
             ::log::debug!($s $(, $x)*);
             #[cfg(feature = "defmt-impl")]
             ::defmt::debug!($s $(, $x)*);
-            #[cfg(not(any(feature = "log", feature="defmt")))]
+            #[cfg(not(any(feature = "log", feature = "defmt-impl")))]
             let _ = ($( & $x ),*);
         }
     };
@@ -35,7 +38,7 @@ macro_rules! info {
             ::log::info!($s $(, $x)*);
             #[cfg(feature = "defmt-impl")]
             ::defmt::info!($s $(, $x)*);
-            #[cfg(not(any(feature = "log", feature="defmt")))]
+            #[cfg(not(any(feature = "log", feature = "defmt-impl")))]
             let _ = ($( & $x ),*);
         }
     };
@@ -48,7 +51,7 @@ macro_rules! warn {
             ::log::warn!($s $(, $x)*);
             #[cfg(feature = "defmt-impl")]
             ::defmt::warn!($s $(, $x)*);
-            #[cfg(not(any(feature = "log", feature="defmt")))]
+            #[cfg(not(any(feature = "log", feature = "defmt-impl")))]
             let _ = ($( & $x ),*);
         }
     };
@@ -61,8 +64,53 @@ macro_rules! error {
             ::log::error!($s $(, $x)*);
             #[cfg(feature = "defmt-impl")]
             ::defmt::error!($s $(, $x)*);
-            #[cfg(not(any(feature = "log", feature="defmt")))]
+            #[cfg(not(any(feature = "log", feature = "defmt-impl")))]
             let _ = ($( & $x ),*);
         }
     };
-}
\ No newline at end of file
+}
+
+#[cfg(all(test, feature = "log"))]
+mod tests {
+    use crate::sim::{SimTimer, Simulated};
+    use crate::NrfMonotonic;
+    use rtic_monotonic::Monotonic;
+    use std::sync::Mutex;
+
+    /// Collects the messages of all tests, they run in parallel
+    struct Collect(Mutex<Vec<String>>);
+
+    impl log::Log for Collect {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: Collect = Collect(Mutex::new(Vec::new()));
+
+    #[test]
+    fn trace_points_reach_log() {
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let sim = SimTimer::<4>::new();
+        let mut mono = NrfMonotonic::<_>::new(sim);
+        unsafe { mono.reset() };
+        let now = mono.now();
+        mono.set_compare(now);
+        sim.advance(0x8000_0000);
+        mono.clear_compare_flag();
+        mono.on_interrupt();
+
+        let messages = LOGGER.0.lock().unwrap();
+        for expected in ["Compare flag cleared", "Overflow, period: 1"] {
+            assert!(messages.iter().any(|m| m == expected), "{} missing", expected);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! Extension of a free running counter to 64 bits by counting half periods
+//!
+//! The monotonics increment `period` twice per counter period: when the counter reaches the
+//! middle of its range and when it wraps around. As long as the interrupt doing so is handled
+//! within half a counter period, `period` is either the number of half periods that passed or
+//! lags behind by one.
+
+/// Combine the half-period count with the raw value of a `BITS` wide counter.
+///
+/// The top bit of the counter must agree with the parity of `period`. If the counter already
+/// crossed into the next half period but the interrupt has not been handled yet, the XOR makes
+/// the value wrap into the next period instead of going back in time.
+#[inline(always)]
+pub(crate) const fn calc_now<const BITS: u32>(period: u64, counter: u32) -> u64 {
+    let top = BITS - 1;
+    let counter = counter as u64 & ((1 << BITS) - 1);
+    (period << top) + (counter ^ ((period & 1) << top))
+}
+
+/// `calc_now` for a 16, 24 or 32-bit counter selected at runtime
+#[inline(always)]
+pub(crate) fn calc_now_bits(bits: u32, period: u64, counter: u32) -> u64 {
+    match bits {
+        16 => calc_now::<16>(period, counter),
+        24 => calc_now::<24>(period, counter),
+        _ => calc_now::<32>(period, counter),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::calc_now;
+
+    /// The `period` values an interrupt handled within half a period can leave at time `t`
+    fn observable_periods<const BITS: u32>(t: u64) -> impl Iterator<Item = u64> {
+        let half_periods = t >> (BITS - 1);
+        half_periods
+            .checked_sub(1)
+            .into_iter()
+            .chain([half_periods])
+    }
+
+    /// Check `calc_now` at time `t` for every `period` that can be observed
+    fn check<const BITS: u32>(t: u64) {
+        let counter = (t & ((1 << BITS) - 1)) as u32;
+        for period in observable_periods::<BITS>(t) {
+            assert_eq!(
+                calc_now::<BITS>(period, counter),
+                t,
+                "{} bits, period {}, counter {:#x}",
+                BITS,
+                period,
+                counter
+            );
+        }
+    }
+
+    /// xorshift64, good enough to pick test cases
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, limit: u64) -> u64 {
+            self.next() % limit
+        }
+    }
+
+    #[test]
+    fn exhaustive_narrow_counters() {
+        for t in 0..1 << 12 {
+            check::<4>(t);
+            check::<8>(t);
+        }
+    }
+
+    #[test]
+    fn exhaustive_24_bit_counter() {
+        // the first periods and the ones around 2^32 half periods
+        for start in [0, (u32::MAX as u64 - 3) << 23] {
+            for t in start..start + (4 << 23) {
+                check::<24>(t);
+            }
+        }
+    }
+
+    #[test]
+    fn half_period_boundaries() {
+        for bits_boundary in [1u64 << 15, 1 << 23, 1 << 31] {
+            for half_periods in [1, 2, 3, 4, 255, 256, 257, 1 << 20] {
+                let boundary = half_periods * bits_boundary;
+                for t in boundary - 2..=boundary + 2 {
+                    match bits_boundary {
+                        0x8000 => check::<16>(t),
+                        0x80_0000 => check::<24>(t),
+                        _ => check::<32>(t),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rtc_counter_wrap() {
+        // the counter wraps from 0xFF_FFFF to 0 at the end of every odd half period
+        assert_eq!(calc_now::<24>(1, 0xFF_FFFF), 0xFF_FFFF);
+        assert_eq!(calc_now::<24>(1, 0), 0x100_0000);
+        assert_eq!(calc_now::<24>(2, 0), 0x100_0000);
+        assert_eq!(calc_now::<24>(2, 0x7F_FFFF), 0x17F_FFFF);
+        assert_eq!(calc_now::<24>(2, 0x80_0000), 0x180_0000);
+        assert_eq!(calc_now::<24>(3, 0x80_0000), 0x180_0000);
+    }
+
+    #[test]
+    fn bits_above_the_counter_are_ignored() {
+        assert_eq!(calc_now::<16>(0, 0xABCD_1234), 0x1234);
+        assert_eq!(calc_now::<24>(0, 0xFF12_3456), 0x12_3456);
+    }
+
+    #[test]
+    fn lagging_a_full_half_period_goes_back_in_time() {
+        // documents the latency limit: two missed half periods cannot be told apart
+        assert_ne!(calc_now::<24>(0, 0), 1 << 24);
+    }
+
+    /// Randomly interleave counter reads with interrupts delayed by up to half a period
+    fn random_interleaving<const BITS: u32>(seed: u64) {
+        let half = 1u64 << (BITS - 1);
+        let mut rng = Rng(seed);
+        let mut t = rng.below(half * 8);
+        let mut period = t >> (BITS - 1);
+        let mut last = 0;
+        for _ in 0..100_000 {
+            // the time at which the current half period started
+            let boundary = (t >> (BITS - 1)) << (BITS - 1);
+            if period < t >> (BITS - 1) && rng.below(4) == 0 {
+                // the interrupt handler runs
+                period += 1;
+            } else if period < t >> (BITS - 1) {
+                // still pending, time passes but stays within half a period of the boundary
+                t += rng.below(boundary + half - t);
+            } else {
+                t += match rng.below(3) {
+                    0 => 1,
+                    1 => rng.below(half),
+                    _ => boundary + half - t,
+                };
+            }
+
+            let now = calc_now::<BITS>(period, (t & ((1 << BITS) - 1)) as u32);
+            assert_eq!(now, t);
+            assert!(now >= last);
+            last = now;
+        }
+    }
+
+    #[test]
+    fn random_interleavings_are_monotonic() {
+        for seed in 1..=20 {
+            random_interleaving::<8>(seed);
+            random_interleaving::<16>(seed);
+            random_interleaving::<24>(seed);
+            random_interleaving::<32>(seed);
+        }
+    }
+}
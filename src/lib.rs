@@ -1,8 +1,49 @@
 //! # `Monotonic` implementation based on DWT and SysTick
 
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![allow(unused_macros)]
 
+/// Emit a compile error for every pair of enabled chip features
+macro_rules! exclusive_chip_features {
+    ($first:literal $(, $rest:literal)*) => {
+        $(
+            #[cfg(all(feature = $first, feature = $rest))]
+            compile_error!(concat!(
+                "the chip features `", $first, "` and `", $rest,
+                "` are mutually exclusive, enable exactly one of them"
+            ));
+        )*
+        exclusive_chip_features!($($rest),*);
+    };
+    () => {};
+}
+
+exclusive_chip_features!(
+    "51", "52810", "52811", "52820", "52832", "52833", "52840", "5340-app", "5340-net", "9160"
+);
+
+#[cfg(not(any(
+    feature = "51",
+    feature = "52810",
+    feature = "52811",
+    feature = "52820",
+    feature = "52832",
+    feature = "52833",
+    feature = "52840",
+    feature = "5340-app",
+    feature = "5340-net",
+    feature = "9160"
+)))]
+compile_error!(
+    "no chip selected, enable exactly one of the features `51`, `52810`, `52811`, `52820`, \
+     `52832`, `52833`, `52840`, `5340-app`, `5340-net` or `9160`"
+);
+
+#[cfg(all(feature = "log", feature = "defmt-impl"))]
+compile_error!(
+    "the logging backends `log` and `defmt-impl` are mutually exclusive, enable at most one of them"
+);
+
 #[cfg(feature = "51")]
 use nrf51_hal as hal;
 
@@ -12,17 +53,56 @@ use nrf52810_hal as hal;
 #[cfg(feature = "52811")]
 use nrf52811_hal as hal;
 
+#[cfg(feature = "52820")]
+mod hal {
+    // nrf52820-hal is not published and nrf-hal-common has no `52820` feature, the monotonics
+    // only need the PAC
+    pub use nrf52820_pac as pac;
+}
+
 #[cfg(feature = "52832")]
 use nrf52832_hal as hal;
 
+#[cfg(feature = "52833")]
+use nrf52833_hal as hal;
+
 #[cfg(feature = "52840")]
 use nrf52840_hal as hal;
 
+#[cfg(feature = "5340-app")]
+use nrf5340_app_hal as hal;
+
+#[cfg(feature = "5340-net")]
+use nrf5340_net_hal as hal;
+
+#[cfg(feature = "9160")]
+use nrf9160_hal as hal;
+
 mod fmt_helpers;
 
+mod half_period;
+
+mod alarm;
+pub use alarm::{Alarm, CompareRegisters, OutOfRange, SharedPeriod, Split};
+
+#[cfg(test)]
+mod sim;
+
+mod clocks;
+pub use clocks::{ClockError, ClockRegisters};
+
+mod timer_instance;
+pub use timer_instance::{Bitmode, Instance16, Instance32, TimerRegisters};
+
+mod rtc_instance;
+pub use rtc_instance::{RtcInstance, RtcRegisters};
+
 mod timer_monotonic;
 pub use timer_monotonic::NrfMonotonic;
 
+mod timer16_monotonic;
+pub use timer16_monotonic::NrfMonotonic16;
+
 pub use fugit::{
     MicrosDurationU32 as Microseconds, MillisDurationU32 as Milliseconds,
     SecsDurationU32 as Seconds,
@@ -32,8 +112,81 @@ pub use fugit::{
 mod since_epoch_monotonic;
 pub use since_epoch_monotonic::MonoTimer;
 
-// mod rtc_monotonic;
-// pub use rtc_monotonic::RtcMonotonic;
+// the hybrid needs a PPI with fork tasks
+#[cfg(not(any(
+    feature = "51",
+    feature = "5340-app",
+    feature = "5340-net",
+    feature = "9160"
+)))]
+mod ppi;
+#[cfg(not(any(
+    feature = "51",
+    feature = "5340-app",
+    feature = "5340-net",
+    feature = "9160"
+)))]
+pub use ppi::PpiRegisters;
+
+#[cfg(not(any(
+    feature = "51",
+    feature = "5340-app",
+    feature = "5340-net",
+    feature = "9160"
+)))]
+mod rtc_monotonic;
+#[cfg(not(any(
+    feature = "51",
+    feature = "5340-app",
+    feature = "5340-net",
+    feature = "9160"
+)))]
+pub use rtc_monotonic::RtcMonotonic;
+
+#[cfg(not(any(
+    feature = "51",
+    feature = "5340-app",
+    feature = "5340-net",
+    feature = "9160"
+)))]
+mod calibration;
+#[cfg(not(any(
+    feature = "51",
+    feature = "5340-app",
+    feature = "5340-net",
+    feature = "9160"
+)))]
+pub use calibration::{Calibration, Drift};
+
+mod rtc_monotonic_v2;
+pub use rtc_monotonic_v2::{RtcClock, RtcMono};
+
+mod tickless_monotonic;
+pub use tickless_monotonic::TicklessMonotonic;
+
+mod delay;
+pub use delay::Delay;
+
+mod wall_clock;
+pub use wall_clock::{StaleSync, WallClock, SLEW_PPM, SLEW_SECONDS};
+
+#[cfg(feature = "stats")]
+mod stats;
+#[cfg(feature = "stats")]
+pub use stats::{MonotonicStats, Stats};
+
+#[cfg(feature = "async-delay")]
+mod async_delay;
+#[cfg(feature = "async-delay")]
+pub use async_delay::{AlarmWaker, AsyncDelay};
+
+#[cfg(feature = "defmt-timestamp")]
+pub mod timestamp;
+
+#[cfg(feature = "embassy-time")]
+mod embassy_driver;
+#[cfg(feature = "embassy-time")]
+pub use embassy_driver::EmbassyDriver;
 
-// mod rtc_monotonic_v2;
-// pub use rtc_monotonic_v2::RtcMono;
+#[cfg(feature = "rtic-v2")]
+pub mod rtic_v2;
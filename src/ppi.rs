@@ -0,0 +1,29 @@
+//! PPI channels connecting the RTC and TIMER of `RtcMonotonic`
+//!
+//! Only the nRF52 family has a PPI with fork tasks, the nRF51 has no forks and the nRF5340 and
+//! nRF9160 have a DPPI instead.
+
+use crate::hal;
+
+/// Register level access to the PPI
+///
+/// Implemented for the PAC peripheral and by a simulated PPI to test `RtcMonotonic` on the host.
+pub trait PpiRegisters {
+    /// Route the event at address `eep` of channel `ch` to the task at `tep`, and to the fork
+    /// task at `fork` unless it is 0
+    fn connect(&self, ch: usize, eep: u32, tep: u32, fork: u32);
+    fn enable(&self, ch: usize);
+}
+
+impl PpiRegisters for hal::pac::PPI {
+    fn connect(&self, ch: usize, eep: u32, tep: u32, fork: u32) {
+        self.ch[ch].eep.write(|w| unsafe { w.bits(eep) });
+        self.ch[ch].tep.write(|w| unsafe { w.bits(tep) });
+        self.fork[ch].tep.write(|w| unsafe { w.bits(fork) });
+    }
+
+    #[inline(always)]
+    fn enable(&self, ch: usize) {
+        self.chenset.write(|w| unsafe { w.bits(1 << ch) });
+    }
+}
@@ -0,0 +1,221 @@
+//! RTC instances usable by the monotonics of this crate
+//!
+//! RTC0 of the nRF52 only has three capture/compare channels, all other instances have four.
+
+use crate::alarm::{CompareRegisters, SharedPeriod};
+use crate::hal;
+#[cfg(feature = "stats")]
+use crate::stats::Stats;
+
+#[cfg(not(any(feature = "5340-app", feature = "5340-net", feature = "9160")))]
+use hal::pac::rtc0;
+#[cfg(any(feature = "5340-app", feature = "5340-net", feature = "9160"))]
+use hal::pac::rtc0_ns as rtc0;
+
+pub(crate) use rtc0::RegisterBlock as RtcRegister;
+
+/// Closest distance of a compare value to the counter: COUNTER or COUNTER + 1 may not trigger an
+/// event, and the counter may tick once more between reading it and writing the value
+pub(crate) const MIN_COMPARE_DISTANCE: u64 = 3;
+
+#[cfg(not(any(feature = "5340-app", feature = "5340-net", feature = "9160")))]
+use hal::pac::{RTC0, RTC1};
+
+#[cfg(any(feature = "52832", feature = "52833", feature = "52840"))]
+use hal::pac::RTC2;
+
+#[cfg(any(feature = "5340-app", feature = "5340-net", feature = "9160"))]
+use hal::pac::{RTC0_NS, RTC1_NS};
+
+#[cfg(any(feature = "5340-app", feature = "9160"))]
+use hal::pac::{RTC0_S, RTC1_S};
+
+/// Register level access to an RTC
+///
+/// The monotonics only touch their RTC through this trait. It is implemented for the PAC register
+/// block and can be implemented by a simulated RTC to test the monotonics on the host.
+/// Channel numbers must be below the `CC_CHANNELS` of the instance the registers belong to.
+pub trait RtcRegisters: CompareRegisters {
+    fn task_start(&self);
+    fn task_stop(&self);
+    fn task_clear(&self);
+    /// The prescaler can only be written while the RTC is stopped
+    fn set_prescaler(&self, prescaler: u16);
+    fn counter(&self) -> u32;
+    fn is_overflow(&self) -> bool;
+    fn clear_overflow(&self);
+    fn enable_overflow_interrupt(&self);
+    /// Route the compare event of channel `n` to the PPI
+    fn enable_compare_event(&self, n: usize);
+    /// Address of the compare event of channel `n`, to be routed through the PPI
+    fn compare_event_address(&self, n: usize) -> u32;
+    /// Route the TICK event, one per counter increment, to the PPI
+    fn enable_tick_event(&self);
+    fn disable_tick_event(&self);
+    /// Address of the TICK event, to be routed through the PPI
+    fn tick_event_address(&self) -> u32;
+}
+
+impl RtcRegisters for RtcRegister {
+    #[inline(always)]
+    fn task_start(&self) {
+        self.tasks_start.write(|w| unsafe { w.bits(1) });
+    }
+
+    #[inline(always)]
+    fn task_stop(&self) {
+        self.tasks_stop.write(|w| unsafe { w.bits(1) });
+    }
+
+    #[inline(always)]
+    fn task_clear(&self) {
+        self.tasks_clear.write(|w| unsafe { w.bits(1) });
+    }
+
+    #[inline(always)]
+    fn set_prescaler(&self, prescaler: u16) {
+        self.prescaler
+            .write(|w| unsafe { w.prescaler().bits(prescaler) });
+    }
+
+    #[inline(always)]
+    fn counter(&self) -> u32 {
+        self.counter.read().bits()
+    }
+
+    #[inline(always)]
+    fn is_overflow(&self) -> bool {
+        self.events_ovrflw.read().bits() != 0
+    }
+
+    #[inline(always)]
+    fn clear_overflow(&self) {
+        self.events_ovrflw.reset();
+    }
+
+    #[inline(always)]
+    fn enable_overflow_interrupt(&self) {
+        self.intenset.write(|w| w.ovrflw().set_bit());
+    }
+
+    #[inline(always)]
+    fn enable_compare_event(&self, n: usize) {
+        self.evtenset.write(|w| unsafe { w.bits(1 << (16 + n)) });
+    }
+
+    #[inline(always)]
+    fn compare_event_address(&self, n: usize) -> u32 {
+        &self.events_compare[n] as *const _ as u32
+    }
+
+    #[inline(always)]
+    fn enable_tick_event(&self) {
+        self.evtenset.write(|w| w.tick().set_bit());
+    }
+
+    #[inline(always)]
+    fn disable_tick_event(&self) {
+        self.evtenclr.write(|w| w.tick().set_bit());
+    }
+
+    #[inline(always)]
+    fn tick_event_address(&self) -> u32 {
+        &self.events_tick as *const _ as u32
+    }
+}
+
+impl CompareRegisters for RtcRegister {
+    #[inline(always)]
+    fn set_cc(&self, n: usize, value: u32) {
+        self.cc[n].write(|w| unsafe { w.bits(value) });
+    }
+
+    #[inline(always)]
+    fn is_compare(&self, n: usize) -> bool {
+        self.events_compare[n].read().bits() != 0
+    }
+
+    #[inline(always)]
+    fn clear_compare(&self, n: usize) {
+        self.events_compare[n].reset();
+    }
+
+    #[inline(always)]
+    fn enable_compare_interrupt(&self, n: usize) {
+        self.intenset.write(|w| unsafe { w.bits(1 << (16 + n)) });
+    }
+
+    #[inline(always)]
+    fn disable_compare_interrupt(&self, n: usize) {
+        self.intenclr.write(|w| unsafe { w.bits(1 << (16 + n)) });
+    }
+
+    #[inline(always)]
+    fn is_compare_interrupt_enabled(&self, n: usize) -> bool {
+        self.intenset.read().bits() & (1 << (16 + n)) != 0
+    }
+}
+
+/// An RTC instance
+pub trait RtcInstance {
+    /// Number of capture/compare channels of this instance
+    const CC_CHANNELS: usize;
+
+    type Registers: RtcRegisters + 'static;
+
+    /// The registers of this instance
+    fn as_rtc0(&self) -> &'static Self::Registers;
+
+    /// The half-period count of the monotonic running on this instance
+    fn shared_period(&self) -> &'static SharedPeriod;
+
+    /// The event counters of the monotonic running on this instance
+    #[cfg(feature = "stats")]
+    fn shared_stats(&self) -> &'static Stats;
+}
+
+macro_rules! impl_rtc_instance {
+    ($channels:expr => $($name:ident,)*) => {
+        $(
+            impl RtcInstance for $name {
+                const CC_CHANNELS: usize = $channels;
+
+                type Registers = RtcRegister;
+
+                #[inline(always)]
+                fn as_rtc0(&self) -> &'static RtcRegister {
+                    // SAFETY: the register block is valid for the whole program
+                    unsafe { &*$name::ptr() }
+                }
+
+                #[inline(always)]
+                fn shared_period(&self) -> &'static SharedPeriod {
+                    static PERIOD: SharedPeriod = SharedPeriod::new();
+                    &PERIOD
+                }
+
+                #[cfg(feature = "stats")]
+                #[inline(always)]
+                fn shared_stats(&self) -> &'static Stats {
+                    static STATS: Stats = Stats::new();
+                    &STATS
+                }
+            }
+        )*
+    }
+}
+
+#[cfg(not(any(feature = "5340-app", feature = "5340-net", feature = "9160")))]
+impl_rtc_instance!(3 => RTC0,);
+
+#[cfg(not(any(feature = "5340-app", feature = "5340-net", feature = "9160")))]
+impl_rtc_instance!(4 => RTC1,);
+
+#[cfg(any(feature = "52832", feature = "52833", feature = "52840"))]
+impl_rtc_instance!(4 => RTC2,);
+
+#[cfg(any(feature = "5340-app", feature = "5340-net", feature = "9160"))]
+impl_rtc_instance!(4 => RTC0_NS, RTC1_NS,);
+
+#[cfg(any(feature = "5340-app", feature = "9160"))]
+impl_rtc_instance!(4 => RTC0_S, RTC1_S,);
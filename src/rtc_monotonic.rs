@@ -1,157 +1,463 @@
-use core::convert::TryInto;
-use core::sync::atomic::compiler_fence;
+use crate::alarm::CompareRegisters;
+use crate::clocks::{check_lfclk, ClockError, ClockRegisters};
+use crate::half_period::calc_now;
+use crate::ppi::PpiRegisters;
+use crate::rtc_instance::{RtcInstance, RtcRegisters, MIN_COMPARE_DISTANCE};
+use crate::timer_instance::{prescaler, Bitmode, Instance32, TimerRegisters};
+use rtic_monotonic::Monotonic;
 
-use crate::hal;
-
-use hal::rtc::Instance as RtcInstance;
-use hal::timer::Instance as TimerInstance;
-use rtic_monotonic::{Clock, Fraction, Instant, Microseconds, Monotonic};
-
-pub struct RtcMonotonic<RTC: RtcInstance, TIM: TimerInstance> {
-    tim: TIM,
+/// Monotonic with microsecond resolution at the idle current of the RTC
+///
+/// The RTC keeps the time: its 24-bit counter is extended to 64 bits by counting half periods,
+/// from a compare event on the last channel alternating between the middle and the start of
+/// the counter range. The TIMER runs at 1MHz from the HFCLK, but only for the final approach
+/// to a deadline: a PPI channel starts it from a compare event on CC0 of the RTC at the last
+/// tick before the deadline, and its own compare interrupt fires at the microsecond. Between
+/// deadlines only the LFCLK runs.
+///
+/// The RTC raises no interrupts, the monotonic has to be bound to the TIMER interrupt. A second
+/// PPI channel starts the TIMER from the half-period event, so it reaches the CPU as well.
+///
+/// Channels: CC0 and the last channel of the RTC, CC0 (compare), CC1 (now) and CC2 (captured
+/// through a fork of the PPI when the approach starts) of the TIMER.
+///
+/// # Accuracy
+///
+/// A deadline is reached less than a microsecond late. The RTC can only start the TIMER from a
+/// compare value at least three ticks ahead, a deadline closer than about 92µs is reached at
+/// the earliest such tick instead, unless the TIMER is still running for a previous deadline.
+/// During the approach the time comes from the HFCLK, which is less accurate on the internal
+/// oscillator than on the crystal.
+///
+/// # Interrupt latency
+///
+/// The TIMER interrupt following a half-period event must be handled within about 90µs, before
+/// an approach starting shortly after it. `now()` stays monotonic as long as it is handled
+/// within half a counter period of the RTC, about 256 seconds.
+pub struct RtcMonotonic<RTC: RtcInstance, TIM: Instance32> {
     rtc: RTC,
-    ovf: u64,
+    timer: TIM,
+    period: u64,
+    /// Deadline of the timer queue in µs, until it is reached
+    deadline: Option<u64>,
+    /// RTC tick at which the PPI starts the TIMER for the approach to `deadline`
+    approach: u64,
+    /// RTC tick at which the running TIMER was started and the TIMER value captured then
+    reference: Option<(u64, u32)>,
+    /// Latest time handed out by `now`
+    last: u64,
 }
 
-impl<RTC: RtcInstance, TIM: TimerInstance> RtcMonotonic<RTC, TIM> {
-    pub fn new(rtc: RTC, tim: TIM) -> Self {
-        {
-            // TIM setup
-            let t0 = tim.as_timer0();
-            t0.mode.write(|w| w.mode().timer());
-            t0.bitmode.write(|w| w.bitmode()._32bit());
-            // 1MHz
-            t0.prescaler.write(|w| unsafe { w.prescaler().bits(4) });
-            t0.tasks_clear.write(|w| w.tasks_clear().set_bit());
+impl<RTC: RtcInstance, TIM: Instance32> RtcMonotonic<RTC, TIM> {
+    const RTC_CC_APPROACH: usize = 0;
+    const RTC_CC_HALF_PERIOD: usize = RTC::CC_CHANNELS - 1;
+    const CC_COMPARE: usize = 0;
+    const CC_NOW: usize = 1;
+    const CC_START: usize = 2;
+    const COUNTER_MASK: u64 = 0x00FF_FFFF;
+    const HALF_PERIOD: u32 = 0x0080_0000;
+    /// Ticks after a half-period event in which no approach starts, the TIMER it started may
+    /// still be running
+    const HALF_PERIOD_TICKS: u64 = 4;
+    /// An RTC tick lasts 1_000_000 / 32_768 = 15625 / 512 µs
+    const TICK_MICROS: (u64, u64) = (15_625, 512);
+
+    /// Stop the RTC and the TIMER, and connect them through the PPI channels `channels`
+    ///
+    /// The LFCLK has to be running, the RTC is started in `reset`. The PPI channels must not be
+    /// used for anything else.
+    pub fn new<PPI: PpiRegisters>(rtc: RTC, timer: TIM, ppi: &PPI, channels: [usize; 2]) -> Self {
+        let (r, t0) = (rtc.as_rtc0(), timer.as_timer0());
+        r.task_stop();
+        r.task_clear();
+        r.set_prescaler(0);
+
+        t0.task_stop();
+        t0.configure(Bitmode::Bits32, prescaler(1_000_000));
+        t0.task_clear();
+
+        ppi.connect(
+            channels[0],
+            r.compare_event_address(Self::RTC_CC_APPROACH),
+            t0.start_task_address(),
+            t0.capture_task_address(Self::CC_START),
+        );
+        ppi.connect(
+            channels[1],
+            r.compare_event_address(Self::RTC_CC_HALF_PERIOD),
+            t0.start_task_address(),
+            0,
+        );
+        for ch in channels {
+            ppi.enable(ch);
+        }
+
+        Self {
+            rtc,
+            timer,
+            period: 0,
+            deadline: None,
+            approach: 0,
+            reference: None,
+            last: 0,
+        }
+    }
+
+    /// `new`, after checking that the LFCLK runs
+    ///
+    /// The TIMER requests the HFCLK itself while it runs. `clock` is the CLOCK peripheral or the
+    /// `Clocks` of the HAL.
+    pub fn try_new<PPI: PpiRegisters>(
+        rtc: RTC,
+        timer: TIM,
+        ppi: &PPI,
+        channels: [usize; 2],
+        clock: &impl ClockRegisters,
+    ) -> Result<Self, ClockError> {
+        check_lfclk(clock)?;
+        Ok(Self::new(rtc, timer, ppi, channels))
+    }
+
+    /// Counter value at which the half period following `period` starts
+    #[inline(always)]
+    fn next_period_start(period: u64) -> u32 {
+        if period & 1 == 0 {
+            Self::HALF_PERIOD
+        } else {
+            0
         }
+    }
+
+    #[inline(always)]
+    fn rtc_ticks(&self) -> u64 {
+        calc_now::<24>(self.period, self.rtc.as_rtc0().counter())
+    }
+
+    /// Start of RTC tick `tick` in µs, rounded up
+    #[inline(always)]
+    fn tick_start(tick: u64) -> u64 {
+        (tick * Self::TICK_MICROS.0).div_ceil(Self::TICK_MICROS.1)
+    }
+
+    /// The RTC tick `deadline` falls into
+    #[inline(always)]
+    fn tick_of(deadline: u64) -> u64 {
+        deadline * Self::TICK_MICROS.1 / Self::TICK_MICROS.0
+    }
+
+    /// TIMER ticks from the start of RTC tick `tick` to `deadline`, at least one
+    fn remaining(deadline: u64, tick: u64) -> u32 {
+        let (num, den) = Self::TICK_MICROS;
+        (deadline * den)
+            .saturating_sub(tick * num)
+            .div_ceil(den)
+            .max(1) as u32
+    }
+
+    /// RTC tick from which the TIMER approaches `deadline`, not before `earliest`
+    ///
+    /// The half-period event starts the TIMER as well. An approach starts together with it
+    /// rather than while the TIMER it started may still run.
+    fn approach_tick(deadline: u64, earliest: u64) -> u64 {
+        let half = Self::HALF_PERIOD as u64;
+        let after_half_period =
+            |tick: u64| tick > half && (1..=Self::HALF_PERIOD_TICKS).contains(&(tick % half));
+
+        let mut tick = Self::tick_of(deadline);
+        if after_half_period(tick) {
+            tick -= tick % half;
+        }
+        if tick < earliest {
+            tick = earliest;
+            if after_half_period(tick) {
+                tick += Self::HALF_PERIOD_TICKS + 1 - tick % half;
+            }
+        }
+        tick
+    }
+
+    /// The tick the running TIMER counts from, once the approach to the deadline started
+    fn reference(&mut self, ticks: u64) -> Option<(u64, u32)> {
+        if self.reference.is_none()
+            && self.deadline.is_some()
+            && ticks >= self.approach
+            && self.rtc.as_rtc0().is_compare(Self::RTC_CC_APPROACH)
         {
-            // RTC setup
-            rtc.tasks_clear.write(|w| w.tasks_clear().set_bit());
-            rtc.intenset.write(|w| w.ovrflw().set_bit());
-            rtc.prescaler.write(|w| unsafe { w.prescaler().bits(0) });
-            // rtc.
+            let start = self.timer.as_timer0().read_cc(Self::CC_START);
+            self.reference = Some((self.approach, start));
         }
-        Self { tim, rtc, ovf: 0 }
+        self.reference
     }
 
-    fn is_overflow(&self) -> bool {
-        self.rtc.events_ovrflw.read().events_ovrflw().bit()
+    /// Stop and clear the TIMER, the next start from the PPI interrupts after `compare` ticks
+    fn stop_timer(&self, compare: u32) {
+        let t0 = self.timer.as_timer0();
+        t0.task_stop();
+        t0.task_clear();
+        t0.set_cc(Self::CC_COMPARE, compare);
     }
 
-    fn clear_overflow_flag(&self) {
-        self.rtc
-            .events_ovrflw
-            .write(|w| w.events_ovrflw().clear_bit());
+    /// Forget the reached deadline, a start from the PPI interrupts right away
+    fn idle(&mut self) {
+        self.deadline = None;
+        self.reference = None;
+        self.rtc.as_rtc0().clear_compare(Self::RTC_CC_APPROACH);
+        self.stop_timer(1);
     }
-}
 
-impl<RTC: RtcInstance, TIM: TimerInstance> Clock for RtcMonotonic<RTC, TIM> {
-    type T = u64;
+    /// Let the RTC start the TIMER for the deadline, or keep the TIMER running if the RTC
+    /// cannot start it in time again
+    fn arm(&mut self, now: u64) {
+        let deadline = match self.deadline {
+            Some(deadline) if deadline > now => deadline,
+            _ => {
+                self.idle();
+                return;
+            }
+        };
 
-    const SCALING_FACTOR: Fraction = Fraction::new(1, 32_768);
+        let ticks = self.rtc_ticks();
+        let earliest = ticks + MIN_COMPARE_DISTANCE;
+        if let Some((tick, start)) = self.reference(ticks) {
+            if Self::tick_of(deadline) < earliest {
+                let compare = start.wrapping_add((deadline - Self::tick_start(tick)) as u32);
+                self.timer.as_timer0().set_cc(Self::CC_COMPARE, compare);
+                return;
+            }
+        }
 
-    fn try_now(
-        &self,
-    ) -> Result<rtic_monotonic::Instant<Self>, rtic_monotonic::embedded_time::clock::Error> {
-        let cnt = self.rtc.counter.read().bits();
-        let cnt = cnt as u64 | self.ovf;
-        trace!("Now: {:010x}", cnt);
-        Ok(Instant::new(cnt))
+        self.reference = None;
+        self.approach = Self::approach_tick(deadline, earliest);
+        trace!("approach from tick {}", self.approach);
+        // Approaches more than a counter period away start early, the timer queue will then
+        // set the compare value again.
+        let rtc = self.rtc.as_rtc0();
+        rtc.set_cc(
+            Self::RTC_CC_APPROACH,
+            (self.approach & Self::COUNTER_MASK) as u32,
+        );
+        rtc.clear_compare(Self::RTC_CC_APPROACH);
+        self.stop_timer(Self::remaining(deadline, self.approach));
     }
 }
 
-impl<RTC: RtcInstance, TIM: TimerInstance> Monotonic for RtcMonotonic<RTC, TIM> {
+impl<RTC: RtcInstance, TIM: Instance32> Monotonic for RtcMonotonic<RTC, TIM> {
     const DISABLE_INTERRUPT_ON_EMPTY_QUEUE: bool = false;
 
+    type Instant = fugit::TimerInstantU64<1_000_000>;
+    type Duration = fugit::TimerDurationU64<1_000_000>;
+
     unsafe fn reset(&mut self) {
-        self.ovf = 0;
+        self.period = 0;
+        self.rtc.shared_period().set(0);
+        self.deadline = None;
+        self.approach = 0;
+        self.reference = None;
+        self.last = 0;
         {
-            let t0 = self.tim.as_timer0();
-            t0.tasks_stop.write(|w| w.tasks_stop().set_bit());
-            t0.tasks_clear.write(|w| w.tasks_clear().set_bit());
-
-            // clear events
-            t0.events_compare[0].write(|w| w.events_compare().clear_bit());
-            t0.events_compare[1].write(|w| w.events_compare().clear_bit());
-            t0.events_compare[2].write(|w| w.events_compare().clear_bit());
-            t0.events_compare[3].write(|w| w.events_compare().clear_bit());
-
-            // disable unneeded interrupts
-            t0.intenclr.write(|w| {
-                w.compare1()
-                    .set_bit()
-                    .compare2()
-                    .set_bit()
-                    .compare3()
-                    .set_bit()
-            });
-
-            // enable copmare match and overflow interrupts
-            t0.intenset.write(|w| w.compare0().set_bit());
-
-            // don't start the timer, it is only needed for scheduling/ after setting compare
-            t0.tasks_start.write(|w| w.tasks_start().set_bit());
+            let t0 = self.timer.as_timer0();
+            t0.task_stop();
+            t0.task_clear();
+            for n in 0..TIM::CC_CHANNELS {
+                t0.reset_channel(n);
+            }
+            t0.clear_shorts();
+            t0.set_cc(Self::CC_COMPARE, 1);
+            t0.enable_compare_interrupt(Self::CC_COMPARE);
         }
         {
-            self.rtc.evtenset.write(|w| w.ovrflw().set_bit());
-            self.rtc.tasks_start.write(|w| w.tasks_start().set_bit());
+            let rtc = self.rtc.as_rtc0();
+            rtc.task_stop();
+            rtc.clear_overflow();
+            for n in 0..RTC::CC_CHANNELS {
+                rtc.disable_compare_interrupt(n);
+                rtc.clear_compare(n);
+            }
+
+            rtc.set_cc(Self::RTC_CC_APPROACH, 0);
+            rtc.set_cc(Self::RTC_CC_HALF_PERIOD, Self::next_period_start(0));
+            rtc.enable_compare_event(Self::RTC_CC_APPROACH);
+            rtc.enable_compare_event(Self::RTC_CC_HALF_PERIOD);
+
+            rtc.task_clear();
+            rtc.task_start();
+
+            // wait for counter to clear
+            while rtc.counter() != 0 {}
         }
     }
 
-    fn set_compare(&mut self, instant: &rtic_monotonic::Instant<Self>) {
-        let now = self.try_now().unwrap();
+    fn now(&mut self) -> Self::Instant {
+        let ticks = self.rtc_ticks();
+        let mut now = ticks * Self::TICK_MICROS.0 / Self::TICK_MICROS.1;
+        if let Some((tick, start)) = self.reference(ticks) {
+            let elapsed = self
+                .timer
+                .as_timer0()
+                .capture(Self::CC_NOW)
+                .wrapping_sub(start);
+            now = now.max(Self::tick_start(tick) + elapsed as u64);
+        }
+        // The TIMER runs ahead of the RTC by up to a tick and is stopped after the deadline
+        self.last = self.last.max(now);
+        Self::Instant::from_ticks(self.last)
+    }
 
-        let dur = instant.checked_duration_since(&now);
+    fn set_compare(&mut self, instant: Self::Instant) {
+        let now = self.now().ticks();
+        self.deadline = Some(instant.ticks());
+        self.arm(now);
+    }
 
-        let micros: Microseconds = match dur {
-            Some(x) => match x.try_into() {
-                Ok(Microseconds(0u32)) | Err(_) => {
-                    trace!("failed or 0");
-                    Microseconds(1)
-                }
-                Ok(x) => x,
-            },
-            None => Microseconds(1),
-        };
+    fn clear_compare_flag(&mut self) {
+        self.timer.as_timer0().clear_compare(Self::CC_COMPARE);
+    }
 
-        // need to check for an overflowed value. Horribly inefficient as well
-        let micros = if micros.0 > 0x00FF_FFFF {
-            trace!("New micros value {}", !micros.0);
-            Microseconds((!micros.0) & 0x00FF_FFFF)
-        } else {
-            micros
-        };
+    fn on_interrupt(&mut self) {
+        let rtc = self.rtc.as_rtc0();
+        if rtc.is_compare(Self::RTC_CC_HALF_PERIOD) {
+            rtc.clear_compare(Self::RTC_CC_HALF_PERIOD);
+            self.period += 1;
+            self.rtc.shared_period().set(self.period);
+            rtc.set_cc(
+                Self::RTC_CC_HALF_PERIOD,
+                Self::next_period_start(self.period),
+            );
+            debug!("Half period, period: {}", self.period);
+        }
 
-        compiler_fence(core::sync::atomic::Ordering::SeqCst);
-        trace!("Set compare reg to {}", micros.0);
-        let ticks = micros.0;
-        {
-            let t0 = self.tim.as_timer0();
-            t0.cc[0].write(|w| unsafe { w.bits(ticks as u32 & 0x00FF_FFFF) });
-            compiler_fence(core::sync::atomic::Ordering::SeqCst);
-            t0.tasks_clear.write(|w| w.tasks_clear().set_bit());
+        // Unless the approach is running, the TIMER was started by the half-period event or by
+        // an approach a counter period early
+        let now = self.now().ticks();
+        let ticks = self.rtc_ticks();
+        match self.deadline {
+            Some(deadline) if deadline > now => {
+                if self.reference(ticks).is_none() {
+                    self.stop_timer(Self::remaining(deadline, self.approach));
+                }
+            }
+            _ => self.idle(),
         }
     }
 
-    fn clear_compare_flag(&mut self) {
-        {
-            trace!("Clear compare flag");
-            let t0 = self.tim.as_timer0();
-            t0.events_compare[0].write(|w| w.events_compare().clear_bit());
+    fn zero() -> Self::Instant {
+        Self::Instant::from_ticks(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{wait_until, SimHybrid, SimRtc, SimTimer, Simulated};
+
+    type Mono = RtcMonotonic<&'static SimRtc<4>, &'static SimTimer<4>>;
+    type Duration = <Mono as Monotonic>::Duration;
+
+    fn setup() -> (&'static SimHybrid, Mono) {
+        let sim = SimHybrid::new();
+        let mut mono = RtcMonotonic::new(sim.rtc, sim.timer, sim.ppi, [3, 5]);
+        unsafe { mono.reset() };
+        (sim, mono)
+    }
+
+    /// `wait_until`, checking that the instant is reached within a microsecond
+    fn reach(mono: &mut Mono, sim: &SimHybrid, instant: <Mono as Monotonic>::Instant) {
+        let reached = wait_until(mono, sim, instant);
+        assert!(reached >= instant, "{} before {}", reached, instant);
+        assert!(
+            reached.ticks() - instant.ticks() <= 1,
+            "{} after {}",
+            reached,
+            instant
+        );
+    }
+
+    #[test]
+    fn compare_fires_at_microsecond() {
+        let (sim, mut mono) = setup();
+        for delay in [100, 1234, 30_518, 1_000_000, 300_000_017, 600_000_003, 95] {
+            let instant = mono.now() + Duration::from_ticks(delay);
+            reach(&mut mono, sim, instant);
         }
     }
 
-    fn on_interrupt(&mut self) {
-        trace!("RTC interrupt");
+    #[test]
+    fn timer_only_runs_for_the_approach() {
+        let (sim, mut mono) = setup();
+        let instant = mono.now() + Duration::from_ticks(10_000_007);
+        reach(&mut mono, sim, instant);
+        assert!(sim.timer.total() <= 31, "{} µs", sim.timer.total());
+        assert!(!sim.timer.is_running());
+    }
 
-        if self.is_overflow() {
-            debug!("RTC Overflow");
-            self.clear_overflow_flag();
-            self.ovf += 0x0100_0000;
+    #[test]
+    fn now_follows_the_rtc_and_the_timer() {
+        let (sim, mut mono) = setup();
+        let instant = mono.now() + Duration::from_ticks(2_000);
+        mono.set_compare(instant);
+        mono.on_interrupt();
+
+        // 1/4µs steps through the approach and past the deadline
+        let mut last = 0;
+        for step in 1..=4 * 2_100 {
+            sim.advance(SimHybrid::TIMER_TICK / 4);
+            let now = mono.now().ticks();
+            let time = step / 4;
+            assert!(now >= last);
+            assert!(now + 31 >= time && now <= time + 1, "{} at {}µs", now, time);
+            last = now;
         }
     }
 
-    fn enable_timer(&mut self) {}
+    #[test]
+    fn back_to_back_deadlines() {
+        let (sim, mut mono) = setup();
+        let first = mono.now() + Duration::from_ticks(5_000);
+        reach(&mut mono, sim, first);
+        mono.set_compare(first + Duration::from_ticks(1_000));
+        sim.advance_until_interrupt(u64::MAX);
+
+        // in the interrupt of a deadline, the next one is only microseconds away
+        mono.clear_compare_flag();
+        let now = mono.now();
+        let next = now + Duration::from_ticks(10);
+        mono.set_compare(next);
+        mono.on_interrupt();
+        sim.advance_until_interrupt(u64::MAX);
+        mono.clear_compare_flag();
+        let reached = mono.now();
+        assert!(reached >= next && reached.ticks() - next.ticks() <= 1);
+    }
+
+    #[test]
+    fn deadline_closer_than_the_rtc_can_start() {
+        let (sim, mut mono) = setup();
+        let instant = mono.now() + Duration::from_ticks(50_000);
+        reach(&mut mono, sim, instant);
+        sim.advance(SimHybrid::RTC_TICK * 10);
 
-    fn disable_timer(&mut self) {}
+        let instant = mono.now() + Duration::from_ticks(20);
+        let reached = wait_until(&mut mono, sim, instant);
+        assert!(reached >= instant);
+        assert!(
+            reached.ticks() - instant.ticks() <= 93,
+            "{} after {}",
+            reached,
+            instant
+        );
+    }
+
+    #[test]
+    fn approach_avoids_the_half_period_start() {
+        let half = 0x80_0000;
+        for tick in [half, half + 1, half + 4] {
+            let deadline = tick * 15_625 / 512 + 10;
+            assert_eq!(Mono::approach_tick(deadline, 0), half);
+        }
+        assert_eq!(Mono::approach_tick(0, half + 2), half + 5);
+        assert_eq!(
+            Mono::approach_tick((half + 5) * 15_625 / 512 + 1, 0),
+            half + 5
+        );
+    }
 }
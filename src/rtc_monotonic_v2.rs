@@ -1,131 +1,354 @@
+use crate::alarm::{handle_alarms, Alarm, CompareRegisters, SharedPeriod, Split};
 #[allow(unused)]
 #[cfg(feature = "defmt-impl")]
 use crate::fmt_helpers::*;
-use crate::hal;
+use crate::clocks::{check_lfclk, ClockError, ClockRegisters};
+use crate::half_period::calc_now;
+use crate::rtc_instance::{RtcInstance, RtcRegisters, MIN_COMPARE_DISTANCE};
+#[cfg(feature = "stats")]
+use crate::stats::Stats;
+use rtic_monotonic::Monotonic;
 
-use hal::rtc::Instance as RtcInstance;
-use rtic_monotonic::embedded_time::clock::Error as ClockError;
-use rtic_monotonic::{Clock, Fraction, Instant, Monotonic};
+pub const RTC_HZ: u32 = 32_768;
 
+/// Channels left for alarms on an RTC with four channels
+const MAX_ALARMS: usize = 2;
+
+/// Monotonic Timer based on the NRF RTC peripheral
+///
+/// The RTC runs from the LFCLK at 32.768kHz. Its 24-bit counter is extended to 64 bits by
+/// counting half periods: the overflow event and a compare event at the middle of the counter
+/// range (on the last CC channel, CC3 or CC2 for RTC0 on the nRF52) both increment `period`.
+/// The channels between CC0 and the half-period channel can be split off as alarms.
 pub struct RtcMono<RTC: RtcInstance> {
     rtc: RTC,
-    ovfl: u32,
-}
-
-fn calc_now(period: u32, counter: u32) -> u64 {
-    ((period as u64) << 23) + ((counter ^ ((period & 1) << 23)) as u64)
+    period: u64,
+    callbacks: [Option<fn()>; MAX_ALARMS],
+    /// Target of the last `set_compare` until it is hit
+    #[cfg(feature = "stats")]
+    compare_at: Option<u64>,
 }
 
 impl<RTC: RtcInstance> RtcMono<RTC> {
+    const CC_COMPARE: usize = 0;
+    const CC_HALF_PERIOD: usize = RTC::CC_CHANNELS - 1;
+    const FIRST_ALARM: usize = 1;
+    const COUNTER_MASK: u64 = 0x00FF_FFFF;
+    const HALF_PERIOD: u32 = 0x0080_0000;
+
+    /// Stop the RTC Instance and provide a new `Monotonic` based on it
+    ///
+    /// The LFCLK has to be running, the counter is started in `reset`.
     pub fn new(rtc: RTC) -> Self {
-        rtc.tasks_clear.write(|w| w.tasks_clear().set_bit());
-        rtc.intenset.write(|w| w.ovrflw().set_bit());
-        rtc.prescaler.write(|w| unsafe { w.prescaler().bits(0) });
-        Self { rtc, ovfl: 0 }
+        {
+            let r = rtc.as_rtc0();
+            r.task_stop();
+            r.task_clear();
+            r.set_prescaler(0);
+        }
+        Self {
+            rtc,
+            period: 0,
+            callbacks: [None; MAX_ALARMS],
+            #[cfg(feature = "stats")]
+            compare_at: None,
+        }
+    }
+
+    /// `new`, after checking that the LFCLK runs
+    ///
+    /// `clock` is the CLOCK peripheral or the `Clocks` of the HAL.
+    pub fn try_new(rtc: RTC, clock: &impl ClockRegisters) -> Result<Self, ClockError> {
+        check_lfclk(clock)?;
+        Ok(Self::new(rtc))
+    }
+
+    /// Hand out the free channels from CC1 on as alarms, calling `callbacks[i]` from
+    /// `on_interrupt` when alarm `i` expires
+    ///
+    /// Panics if the instance has less than `N` free channels.
+    pub fn split<const N: usize>(mut self, callbacks: [fn(); N]) -> (Self, [Alarm<Self>; N]) {
+        assert!(
+            Self::FIRST_ALARM + N <= Self::CC_HALF_PERIOD,
+            "not enough free channels for the alarms"
+        );
+        for (slot, callback) in self.callbacks.iter_mut().zip(callbacks) {
+            *slot = Some(callback);
+        }
+        let registers = self.rtc.as_rtc0();
+        let period = self.rtc.shared_period();
+        let alarms = core::array::from_fn(|i| Alarm::new(registers, period, Self::FIRST_ALARM + i));
+        (self, alarms)
+    }
+
+    /// Event counters of this monotonic, they can be read while RTIC owns it
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> &'static Stats {
+        self.rtc.shared_stats()
+    }
+
+    /// Read access to the time of this monotonic, it can be read while RTIC owns it
+    pub fn clock(&self) -> RtcClock<RTC> {
+        RtcClock {
+            registers: self.rtc.as_rtc0(),
+            period: self.rtc.shared_period(),
+        }
     }
 
     #[inline(always)]
     fn is_overflow(&self) -> bool {
-        self.rtc.events_ovrflw.read().events_ovrflw().bit()
+        self.rtc.as_rtc0().is_overflow()
     }
 
     #[inline(always)]
     fn clear_overflow_flag(&self) {
-        self.rtc
-            .events_ovrflw
-            .write(|w| w.events_ovrflw().clear_bit());
-    }
-
-    // #[inline(always)]
-    // fn is_next_period(&self) -> bool {
-    //     self.rtc.events_compare[3]
-    //         .read()
-    //         .events_compare()
-    //         .bit_is_set()
-    // }
-
-    // #[inline(always)]
-    // fn clear_next_period_flag(&self) {
-    //     self.rtc.events_compare[3].write(|w| w.events_compare().clear_bit())
-    // }
-
-    // fn next_period(&self) {
-    // let t = (self.ovfl as u64) << 23;
-    // }
+        self.rtc.as_rtc0().clear_overflow();
+    }
+
+    #[inline(always)]
+    fn is_half_period(&self) -> bool {
+        self.rtc.as_rtc0().is_compare(Self::CC_HALF_PERIOD)
+    }
+
+    #[inline(always)]
+    fn clear_half_period_flag(&self) {
+        self.rtc.as_rtc0().clear_compare(Self::CC_HALF_PERIOD);
+    }
 }
-impl<RTC: RtcInstance> Clock for RtcMono<RTC> {
-    type T = u64;
 
-    const SCALING_FACTOR: Fraction = Fraction::new(1, 32_768);
+/// The time of a running `RtcMono`, read from the counter and the shared half-period count
+pub struct RtcClock<RTC: RtcInstance> {
+    registers: &'static RTC::Registers,
+    period: &'static SharedPeriod,
+}
 
-    fn try_now(&self) -> Result<Instant<Self>, ClockError> {
-        let cnt = self.rtc.counter.read().bits();
-        let now = calc_now(self.ovfl, cnt);
-        trace!("now {:x}", now);
-        Ok(Instant::new(now))
+impl<RTC: RtcInstance> RtcClock<RTC> {
+    /// RTC ticks since the monotonic was reset
+    #[inline(always)]
+    pub fn ticks(&self) -> u64 {
+        calc_now::<24>(self.period.get(), self.registers.counter())
+    }
+
+    #[cfg(any(
+        feature = "defmt-timestamp",
+        not(any(
+            feature = "51",
+            feature = "5340-app",
+            feature = "5340-net",
+            feature = "9160"
+        ))
+    ))]
+    pub(crate) fn registers(&self) -> &'static RTC::Registers {
+        self.registers
+    }
+
+    #[cfg(feature = "defmt-timestamp")]
+    pub(crate) fn period(&self) -> &'static SharedPeriod {
+        self.period
     }
 }
 
 impl<RTC: RtcInstance> Monotonic for RtcMono<RTC> {
+    const DISABLE_INTERRUPT_ON_EMPTY_QUEUE: bool = false;
+
+    type Instant = fugit::TimerInstantU64<{ RTC_HZ }>;
+    type Duration = fugit::TimerDurationU64<{ RTC_HZ }>;
+
+    fn now(&mut self) -> Self::Instant {
+        let cnt = self.rtc.as_rtc0().counter();
+        let now = calc_now::<24>(self.period, cnt);
+        trace!("now {:x}", now);
+        Self::Instant::from_ticks(now)
+    }
+
+    fn zero() -> Self::Instant {
+        Self::Instant::from_ticks(0)
+    }
+
+    unsafe fn reset(&mut self) {
+        self.period = 0;
+        self.rtc.shared_period().set(0);
+        #[cfg(feature = "stats")]
+        {
+            self.compare_at = None;
+            self.rtc.shared_stats().clear();
+        }
+        {
+            let rtc = self.rtc.as_rtc0();
+            rtc.task_stop();
+
+            // clear events
+            rtc.clear_overflow();
+            for n in 0..RTC::CC_CHANNELS {
+                rtc.disable_compare_interrupt(n);
+                rtc.clear_compare(n);
+            }
+
+            // prepare compare registers
+            rtc.set_cc(Self::CC_COMPARE, 0);
+            rtc.set_cc(Self::CC_HALF_PERIOD, Self::HALF_PERIOD);
+
+            rtc.enable_overflow_interrupt();
+            rtc.enable_compare_interrupt(Self::CC_COMPARE);
+            rtc.enable_compare_interrupt(Self::CC_HALF_PERIOD);
+
+            rtc.task_clear();
+            rtc.task_start();
+
+            // wait for counter to clear
+            while rtc.counter() != 0 {}
+        }
+
+        #[cfg(feature = "defmt-timestamp")]
+        crate::timestamp::set_source::<Self>(self.rtc.as_rtc0(), self.rtc.shared_period());
+    }
+
+    fn set_compare(&mut self, instant: Self::Instant) {
+        let now = self.now().ticks();
+        let ticks = instant.ticks().max(now + MIN_COMPARE_DISTANCE);
+        trace!("ticks: {}", ticks);
+        #[cfg(feature = "stats")]
+        {
+            if instant.ticks() < now {
+                self.rtc.shared_stats().past_deadline();
+            }
+            self.compare_at = Some(ticks);
+        }
+
+        // Targets more than a counter period away fire early, the timer queue will then set the
+        // compare value again.
+        self.rtc
+            .as_rtc0()
+            .set_cc(Self::CC_COMPARE, (ticks & Self::COUNTER_MASK) as u32);
+    }
+
+    fn clear_compare_flag(&mut self) {
+        #[cfg(feature = "stats")]
+        if self.rtc.as_rtc0().is_compare(Self::CC_COMPARE) {
+            let now = self.now().ticks();
+            self.rtc
+                .shared_stats()
+                .compare_event(&mut self.compare_at, now);
+        }
+        self.rtc.as_rtc0().clear_compare(Self::CC_COMPARE);
+    }
+
     fn on_interrupt(&mut self) {
         trace!("RTC interrupt");
         if self.is_overflow() {
-            debug!("is overflow");
             self.clear_overflow_flag();
-            self.ovfl += 1;
+            self.period += 1;
+            self.rtc.shared_period().set(self.period);
+            #[cfg(feature = "stats")]
+            self.rtc.shared_stats().overflow();
+            debug!("Overflow, period: {}", self.period);
         }
 
-        // if self.is_next_period() {
-        //     debug!("ts next period");
-        //     self.clear_next_period_flag();
-        // }
+        if self.is_half_period() {
+            self.clear_half_period_flag();
+            self.period += 1;
+            self.rtc.shared_period().set(self.period);
+            #[cfg(feature = "stats")]
+            self.rtc.shared_stats().overflow();
+            debug!("Half period, period: {}", self.period);
+        }
+
+        handle_alarms(self.rtc.as_rtc0(), Self::FIRST_ALARM, &self.callbacks);
     }
+}
 
-    fn enable_timer(&mut self) {}
+impl<RTC: RtcInstance> Split for RtcMono<RTC> {
+    type Registers = RTC::Registers;
 
-    fn disable_timer(&mut self) {}
+    const HZ: u32 = RTC_HZ;
+    const COUNTER_BITS: u32 = 24;
+    /// A compare value of COUNTER or COUNTER + 1 may not trigger an event
+    const MIN_DISTANCE: u64 = MIN_COMPARE_DISTANCE;
 
-    const DISABLE_INTERRUPT_ON_EMPTY_QUEUE: bool = false;
+    #[inline(always)]
+    fn counter(registers: &Self::Registers) -> u32 {
+        registers.counter()
+    }
 
-    unsafe fn reset(&mut self) {
-        {
-            // self.rtc.evtenset.write(|w| w.ovrflw().set_bit());
-            self.rtc.cc[3].write(|w| unsafe { w.bits(0x0080_0000) });
+    #[inline(always)]
+    fn ticks(instant: Self::Instant) -> u64 {
+        instant.ticks()
+    }
+}
 
-            self.rtc.tasks_clear.write(|w| w.tasks_clear().set_bit());
-            self.rtc.tasks_start.write(|w| w.tasks_start().set_bit());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{handle_interrupt, wait_until, SimClock, SimRtc, Simulated};
 
-            // wait for counter to clear
-            while self.rtc.counter.read().bits() != 0 {}
-
-            self.rtc.evtenset.write(|w| {
-                w.compare0()
-                    // .set_bit()
-                    // .compare3()
-                    .set_bit()
-                    .ovrflw()
-                    .set_bit()
-            });
-
-            self.rtc.intenset.write(|w| {
-                w.ovrflw().set_bit().compare0().set_bit()
-                // .compare3()
-                // .set_bit()
-            });
+    type Mono = RtcMono<&'static SimRtc<4>>;
+
+    fn setup(sim: &'static SimRtc<4>) -> Mono {
+        let mut mono = RtcMono::new(sim);
+        unsafe { mono.reset() };
+        mono
+    }
+
+    #[test]
+    fn now_counts_across_overflows() {
+        let sim = SimRtc::new();
+        let mut mono = setup(sim);
+        let step = 0x30_0001;
+        for i in 1..=20u64 {
+            sim.advance(step);
+            handle_interrupt(&mut mono, sim);
+            assert_eq!(mono.now().ticks(), i * step);
         }
     }
 
-    fn set_compare(&mut self, instant: &Instant<Self>) {
-        // let now = self.try_now().unwrap();
-        // let dur = instant.checked_duration_since(&now);
-        let dur = instant.duration_since_epoch();
-        let ticks = dur.integer();
-        trace!("ticks: {}", ticks);
-        self.rtc.cc[0].write(|w| unsafe { w.bits(((ticks + 5) as u32) & 0x00FF_FFFF) })
+    #[test]
+    fn now_tolerates_delayed_interrupt() {
+        let sim = SimRtc::new();
+        let mut mono = setup(sim);
+        let half = 0x80_0000;
+        let mut elapsed = 0;
+        for _ in 0..6 {
+            // run into the next half period, then handle its interrupt almost half a period late
+            for step in [half - elapsed % half, half - 1] {
+                sim.advance(step);
+                elapsed += step;
+                assert_eq!(mono.now().ticks(), elapsed);
+            }
+            handle_interrupt(&mut mono, sim);
+            assert_eq!(mono.now().ticks(), elapsed);
+        }
     }
 
-    fn clear_compare_flag(&mut self) {
-        // trace!("Compare flag cleared");
-        self.rtc.events_compare[0].write(|w| w.events_compare().clear_bit());
+    #[test]
+    fn compare_fires_at_instant() {
+        let sim = SimRtc::new();
+        let mut mono = setup(sim);
+        for delay in [3, 1234, 0x7F_FFFF, 0x80_0001, 0xFF_FFFF, 5 << 24 | 17] {
+            let instant = mono.now() + <Mono as Monotonic>::Duration::from_ticks(delay);
+            assert_eq!(wait_until(&mut mono, sim, instant), instant);
+        }
+    }
+
+    #[test]
+    fn compare_closer_than_min_distance() {
+        let sim = SimRtc::new();
+        let mut mono = setup(sim);
+        for delay in [1, 2] {
+            let instant = mono.now() + <Mono as Monotonic>::Duration::from_ticks(delay);
+            let reached = wait_until(&mut mono, sim, instant);
+            assert!(reached >= instant);
+            assert!(reached.ticks() - instant.ticks() < MIN_COMPARE_DISTANCE);
+        }
+    }
+
+    #[test]
+    fn try_new_needs_the_lfclk() {
+        let clock = SimClock::default();
+        clock.hfxo.set(true);
+        let mono = RtcMono::try_new(SimRtc::<4>::new(), &clock);
+        assert_eq!(mono.err(), Some(ClockError::LfclkNotRunning));
+
+        clock.lfclk.set(true);
+        assert!(RtcMono::try_new(SimRtc::<4>::new(), &clock).is_ok());
     }
 }
@@ -0,0 +1,375 @@
+//! `rtic-time` monotonics for RTIC 2
+//!
+//! RTIC 2 drives its timer queue through the static functions of `rtic_time::Monotonic`. Every
+//! TIMER and RTC instance of the selected chip gets a type here, `Timer0` to `Timer4` and `Rtc0`
+//! to `Rtc2`. It keeps an `NrfMonotonic` (`NrfMonotonic16` on the nRF51) or `RtcMono` in a
+//! critical section mutex and forwards to it, so time keeping and the overflow handling in
+//! `on_interrupt` are the same as with RTIC 1.
+//!
+//! The interrupt handler is bound by the `create_*_token!` macro of the instance, the token it
+//! returns has to be passed to `start`:
+//!
+//! ```ignore
+//! let token = nrf_monotonic::create_nrf_timer1_monotonic_token!();
+//! Timer1::start(cx.device.TIMER1, token);
+//!
+//! Timer1::delay(250.millis()).await;
+//! Timer1::timeout_after(10.millis(), uart.read(&mut buf)).await?;
+//! ```
+//!
+//! On the nRF5340 and the nRF9160 the non-secure instances are used.
+
+use crate::hal::pac::Interrupt;
+use crate::rtc_monotonic_v2::RtcMono;
+use core::cell::RefCell;
+use cortex_m::peripheral::NVIC;
+use critical_section::Mutex;
+pub use rtic_time::{Monotonic, TimeoutError, TimerQueue};
+
+#[cfg(not(feature = "51"))]
+use crate::timer_monotonic::NrfMonotonic;
+
+#[cfg(feature = "51")]
+use crate::timer16_monotonic::NrfMonotonic16 as NrfMonotonic;
+
+/// Proof that the interrupt handler of the monotonic `Mono` is bound
+///
+/// # Safety
+///
+/// Only implemented by the tokens of the `create_*_token!` macros.
+pub unsafe trait InterruptToken<Mono> {}
+
+/// A monotonic of this crate, shared between the timer queue and the interrupt handler
+#[doc(hidden)]
+pub struct Shared<M> {
+    mono: Mutex<RefCell<Option<M>>>,
+}
+
+impl<M: rtic_monotonic::Monotonic> Shared<M> {
+    pub const fn new() -> Self {
+        Self {
+            mono: Mutex::new(RefCell::new(None)),
+        }
+    }
+
+    /// Start `mono` and keep it
+    pub fn start(&self, mut mono: M) {
+        critical_section::with(|cs| {
+            unsafe { mono.reset() };
+            self.mono.borrow_ref_mut(cs).replace(mono);
+        });
+    }
+
+    /// Current time, `zero` before `start`
+    pub fn now(&self) -> M::Instant {
+        critical_section::with(|cs| {
+            self.mono
+                .borrow_ref_mut(cs)
+                .as_mut()
+                .map_or(M::zero(), |mono| mono.now())
+        })
+    }
+
+    pub fn set_compare(&self, instant: M::Instant) {
+        self.with(|mono| mono.set_compare(instant));
+    }
+
+    pub fn clear_compare_flag(&self) {
+        self.with(|mono| mono.clear_compare_flag());
+    }
+
+    pub fn on_interrupt(&self) {
+        self.with(|mono| mono.on_interrupt());
+    }
+
+    fn with(&self, f: impl FnOnce(&mut M)) {
+        critical_section::with(|cs| {
+            if let Some(mono) = self.mono.borrow_ref_mut(cs).as_mut() {
+                f(mono);
+            }
+        });
+    }
+}
+
+impl<M: rtic_monotonic::Monotonic> Default for Shared<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+macro_rules! make_monotonic {
+    ($(#[$attr:meta])* $name:ident, $mono:ty, $periph:ty, $interrupt:ident, $hz:expr) => {
+        $(#[$attr])*
+        pub struct $name;
+
+        impl $name {
+            /// Start the monotonic on `periph` and unmask its interrupt
+            pub fn start(periph: $periph, _interrupt_token: impl InterruptToken<Self>) {
+                Self::shared().start(<$mono>::new(periph));
+                Self::__tq().initialize(Self);
+                unsafe { NVIC::unmask(Interrupt::$interrupt) };
+            }
+
+            fn shared() -> &'static Shared<$mono> {
+                static SHARED: Shared<$mono> = Shared::new();
+                &SHARED
+            }
+
+            /// Used by the interrupt handler of the `create_*_token!` macro
+            #[doc(hidden)]
+            pub fn __tq() -> &'static TimerQueue<Self> {
+                static TIMER_QUEUE: TimerQueue<$name> = TimerQueue::new();
+                &TIMER_QUEUE
+            }
+
+            /// Current time
+            #[inline]
+            pub fn now() -> <Self as Monotonic>::Instant {
+                <Self as Monotonic>::now()
+            }
+
+            /// Delay for at least `duration`
+            #[inline]
+            pub async fn delay(duration: <Self as Monotonic>::Duration) {
+                Self::__tq().delay(duration).await;
+            }
+
+            /// Delay until `instant`
+            #[inline]
+            pub async fn delay_until(instant: <Self as Monotonic>::Instant) {
+                Self::__tq().delay_until(instant).await;
+            }
+
+            /// Run `future` until `instant`
+            #[inline]
+            pub async fn timeout_at<F: core::future::Future>(
+                instant: <Self as Monotonic>::Instant,
+                future: F,
+            ) -> Result<F::Output, TimeoutError> {
+                Self::__tq().timeout_at(instant, future).await
+            }
+
+            /// Run `future` for at least `duration`
+            #[inline]
+            pub async fn timeout_after<F: core::future::Future>(
+                duration: <Self as Monotonic>::Duration,
+                future: F,
+            ) -> Result<F::Output, TimeoutError> {
+                Self::__tq().timeout_after(duration, future).await
+            }
+        }
+
+        impl Monotonic for $name {
+            const ZERO: Self::Instant = fugit::TimerInstantU64::from_ticks(0);
+            const TICK_PERIOD: Self::Duration = fugit::TimerDurationU64::from_ticks(1);
+
+            type Instant = fugit::TimerInstantU64<{ $hz }>;
+            type Duration = fugit::TimerDurationU64<{ $hz }>;
+
+            fn now() -> Self::Instant {
+                Self::shared().now()
+            }
+
+            fn set_compare(instant: Self::Instant) {
+                Self::shared().set_compare(instant);
+            }
+
+            fn clear_compare_flag() {
+                Self::shared().clear_compare_flag();
+            }
+
+            fn pend_interrupt() {
+                NVIC::pend(Interrupt::$interrupt);
+            }
+
+            // The counter keeps running while the queue is empty, so `enable_timer` and
+            // `disable_timer` are not forwarded.
+            fn on_interrupt() {
+                Self::shared().on_interrupt();
+            }
+        }
+    };
+}
+
+/// Bind the interrupt handler of a monotonic and return its `InterruptToken`
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __create_nrf_monotonic_token {
+    ($mono:ident, $interrupt:ident) => {{
+        #[no_mangle]
+        #[allow(non_snake_case)]
+        unsafe extern "C" fn $interrupt() {
+            $crate::rtic_v2::$mono::__tq().on_monotonic_interrupt();
+        }
+
+        pub struct Token;
+
+        unsafe impl $crate::rtic_v2::InterruptToken<$crate::rtic_v2::$mono> for Token {}
+
+        Token
+    }};
+}
+
+#[cfg(not(any(feature = "5340-app", feature = "5340-net", feature = "9160")))]
+use crate::hal::pac::{RTC0, RTC1, TIMER0, TIMER1, TIMER2};
+
+#[cfg(any(feature = "5340-app", feature = "5340-net", feature = "9160"))]
+use crate::hal::pac::{
+    RTC0_NS as RTC0, RTC1_NS as RTC1, TIMER0_NS as TIMER0, TIMER1_NS as TIMER1,
+    TIMER2_NS as TIMER2,
+};
+
+#[cfg(feature = "52820")]
+use crate::hal::pac::TIMER3;
+
+#[cfg(any(feature = "52832", feature = "52833", feature = "52840"))]
+use crate::hal::pac::{RTC2, TIMER3, TIMER4};
+
+make_monotonic!(
+    /// `NrfMonotonic` on TIMER0 at 1MHz
+    Timer0, NrfMonotonic<TIMER0>, TIMER0, TIMER0, 1_000_000
+);
+
+make_monotonic!(
+    /// `NrfMonotonic` on TIMER1 at 1MHz
+    Timer1, NrfMonotonic<TIMER1>, TIMER1, TIMER1, 1_000_000
+);
+
+make_monotonic!(
+    /// `NrfMonotonic` on TIMER2 at 1MHz
+    Timer2, NrfMonotonic<TIMER2>, TIMER2, TIMER2, 1_000_000
+);
+
+#[cfg(any(feature = "52820", feature = "52832", feature = "52833", feature = "52840"))]
+make_monotonic!(
+    /// `NrfMonotonic` on TIMER3 at 1MHz
+    Timer3, NrfMonotonic<TIMER3>, TIMER3, TIMER3, 1_000_000
+);
+
+#[cfg(any(feature = "52832", feature = "52833", feature = "52840"))]
+make_monotonic!(
+    /// `NrfMonotonic` on TIMER4 at 1MHz
+    Timer4, NrfMonotonic<TIMER4>, TIMER4, TIMER4, 1_000_000
+);
+
+make_monotonic!(
+    /// `RtcMono` on RTC0 at 32.768kHz
+    Rtc0, RtcMono<RTC0>, RTC0, RTC0, 32_768
+);
+
+make_monotonic!(
+    /// `RtcMono` on RTC1 at 32.768kHz
+    Rtc1, RtcMono<RTC1>, RTC1, RTC1, 32_768
+);
+
+#[cfg(any(feature = "52832", feature = "52833", feature = "52840"))]
+make_monotonic!(
+    /// `RtcMono` on RTC2 at 32.768kHz
+    Rtc2, RtcMono<RTC2>, RTC2, RTC2, 32_768
+);
+
+/// Bind the TIMER0 interrupt to `Timer0` and return the token for `Timer0::start`
+#[macro_export]
+macro_rules! create_nrf_timer0_monotonic_token {
+    () => {
+        $crate::__create_nrf_monotonic_token!(Timer0, TIMER0)
+    };
+}
+
+/// Bind the TIMER1 interrupt to `Timer1` and return the token for `Timer1::start`
+#[macro_export]
+macro_rules! create_nrf_timer1_monotonic_token {
+    () => {
+        $crate::__create_nrf_monotonic_token!(Timer1, TIMER1)
+    };
+}
+
+/// Bind the TIMER2 interrupt to `Timer2` and return the token for `Timer2::start`
+#[macro_export]
+macro_rules! create_nrf_timer2_monotonic_token {
+    () => {
+        $crate::__create_nrf_monotonic_token!(Timer2, TIMER2)
+    };
+}
+
+/// Bind the TIMER3 interrupt to `Timer3` and return the token for `Timer3::start`
+#[macro_export]
+macro_rules! create_nrf_timer3_monotonic_token {
+    () => {
+        $crate::__create_nrf_monotonic_token!(Timer3, TIMER3)
+    };
+}
+
+/// Bind the TIMER4 interrupt to `Timer4` and return the token for `Timer4::start`
+#[macro_export]
+macro_rules! create_nrf_timer4_monotonic_token {
+    () => {
+        $crate::__create_nrf_monotonic_token!(Timer4, TIMER4)
+    };
+}
+
+/// Bind the RTC0 interrupt to `Rtc0` and return the token for `Rtc0::start`
+#[macro_export]
+macro_rules! create_nrf_rtc0_monotonic_token {
+    () => {
+        $crate::__create_nrf_monotonic_token!(Rtc0, RTC0)
+    };
+}
+
+/// Bind the RTC1 interrupt to `Rtc1` and return the token for `Rtc1::start`
+#[macro_export]
+macro_rules! create_nrf_rtc1_monotonic_token {
+    () => {
+        $crate::__create_nrf_monotonic_token!(Rtc1, RTC1)
+    };
+}
+
+/// Bind the RTC2 interrupt to `Rtc2` and return the token for `Rtc2::start`
+#[macro_export]
+macro_rules! create_nrf_rtc2_monotonic_token {
+    () => {
+        $crate::__create_nrf_monotonic_token!(Rtc2, RTC2)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{SimRtc, SimTimer, Simulated};
+
+    #[test]
+    fn now_before_start() {
+        let shared = Shared::<crate::NrfMonotonic<&'static SimTimer<4>>>::new();
+        assert_eq!(shared.now().ticks(), 0);
+    }
+
+    #[test]
+    fn timer_counts_across_periods() {
+        let sim = SimTimer::<4>::new();
+        let shared = Shared::new();
+        shared.start(crate::NrfMonotonic::<_>::new(sim));
+        for i in 1..=6u64 {
+            sim.advance(0x7000_0000);
+            shared.on_interrupt();
+            assert_eq!(shared.now().ticks(), i * 0x7000_0000);
+        }
+    }
+
+    #[test]
+    fn rtc_compare_raises_interrupt() {
+        let sim = SimRtc::<4>::new();
+        let shared = Shared::new();
+        shared.start(RtcMono::new(sim));
+        sim.advance(0xFF_FF00);
+        shared.on_interrupt();
+
+        let at = shared.now() + fugit::TimerDurationU64::from_ticks(0x200);
+        shared.set_compare(at);
+        while shared.now() < at {
+            sim.advance_until_interrupt(u64::MAX);
+            shared.clear_compare_flag();
+            shared.on_interrupt();
+        }
+        assert_eq!(shared.now(), at);
+    }
+}
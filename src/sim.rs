@@ -0,0 +1,751 @@
+//! Simulated TIMER and RTC peripherals to test the monotonics on the host
+//!
+//! The models implement the register traits of this crate. Time only passes when a test calls
+//! `advance`, which moves the counter, generates compare and overflow events, applies shorts and
+//! raises the interrupt flag for enabled events.
+
+use crate::alarm::{CompareRegisters, SharedPeriod};
+use crate::clocks::ClockRegisters;
+#[cfg(feature = "stats")]
+use crate::stats::Stats;
+use crate::rtc_instance::{RtcInstance, RtcRegisters};
+use crate::timer_instance::{Bitmode, Instance16, Instance32, TimerRegisters};
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU32, Ordering};
+use rtic_monotonic::Monotonic;
+
+/// A peripheral whose time is driven by the test
+pub trait Simulated {
+    /// Let `ticks` counter ticks pass
+    fn advance(&self, ticks: u64);
+
+    /// Let time pass until an enabled event raises the interrupt, at most `limit` ticks
+    ///
+    /// Returns the number of ticks that passed.
+    fn advance_until_interrupt(&self, limit: u64) -> u64;
+
+    fn interrupt_pending(&self) -> bool;
+}
+
+fn cells<const N: usize, T: Copy>(value: T) -> [Cell<T>; N] {
+    [(); N].map(|_| Cell::new(value))
+}
+
+/// A distinct base address for the registers of every simulated peripheral, so PPI endpoints
+/// can tell them apart
+fn base_address() -> u32 {
+    static NEXT: AtomicU32 = AtomicU32::new(0x4000_0000);
+    NEXT.fetch_add(0x1000, Ordering::Relaxed)
+}
+
+/// A TIMER with `N` capture/compare channels
+pub struct SimTimer<const N: usize> {
+    running: Cell<bool>,
+    counter: Cell<u32>,
+    mask: Cell<u32>,
+    prescaler: Cell<u8>,
+    cc: [Cell<u32>; N],
+    events: [Cell<bool>; N],
+    inten: Cell<u32>,
+    shorts: Cell<u32>,
+    total: Cell<u64>,
+    advance_on_capture: Cell<u64>,
+    after_capture: Cell<Option<Box<dyn FnOnce()>>>,
+    base: u32,
+    period: SharedPeriod,
+    #[cfg(feature = "stats")]
+    stats: Stats,
+}
+
+impl<const N: usize> SimTimer<N> {
+    const TASKS_START: u32 = 0x000;
+    const TASKS_CAPTURE: u32 = 0x040;
+
+    /// A new TIMER, leaked to live as long as the registers of a real one
+    pub fn new() -> &'static Self {
+        Box::leak(Box::new(Self {
+            running: Cell::new(false),
+            counter: Cell::new(0),
+            // BITMODE resets to 16 bit
+            mask: Cell::new(0xFFFF),
+            prescaler: Cell::new(4),
+            cc: cells(0),
+            events: cells(false),
+            inten: Cell::new(0),
+            shorts: Cell::new(0),
+            total: Cell::new(0),
+            advance_on_capture: Cell::new(0),
+            after_capture: Cell::new(None),
+            base: base_address(),
+            period: SharedPeriod::new(),
+            #[cfg(feature = "stats")]
+            stats: Stats::new(),
+        }))
+    }
+
+    pub fn counter(&self) -> u32 {
+        self.counter.get()
+    }
+
+    pub fn prescaler(&self) -> u8 {
+        self.prescaler.get()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.get()
+    }
+
+    /// Trigger the task at `address` from the PPI
+    pub fn trigger(&self, address: u32) {
+        let capture = Self::TASKS_CAPTURE..Self::TASKS_CAPTURE + 4 * N as u32;
+        match address.wrapping_sub(self.base) {
+            Self::TASKS_START => self.task_start(),
+            offset if capture.contains(&offset) => {
+                let n = (offset - Self::TASKS_CAPTURE) as usize / 4;
+                self.cc[n].set(self.counter.get());
+            }
+            _ => panic!("no task of this TIMER at {:#x}", address),
+        }
+    }
+
+    /// Ticks that passed while the TIMER was running
+    pub fn total(&self) -> u64 {
+        self.total.get()
+    }
+
+    /// Let `ticks` pass before every capture, to busy-wait on the counter
+    pub fn set_advance_on_capture(&self, ticks: u64) {
+        self.advance_on_capture.set(ticks);
+    }
+
+    /// Run `f` right after the next capture, e.g. an interrupt preempting the code reading it
+    pub fn after_next_capture(&self, f: impl FnOnce() + 'static) {
+        self.after_capture.set(Some(Box::new(f)));
+    }
+
+    /// Set the COMPARE\[n\]_CLEAR short
+    pub fn set_clear_short(&self, n: usize) {
+        self.shorts.set(self.shorts.get() | 1 << n);
+    }
+
+    /// Move the counter forward by at most `limit` ticks, stopping at the first compare match
+    /// if `stop_on_interrupt` is set and the match raised the interrupt
+    fn step(&self, limit: u64, stop_on_interrupt: bool) -> u64 {
+        let mut passed = 0;
+        while passed < limit && self.running.get() {
+            let period = self.mask.get() as u64 + 1;
+            let counter = self.counter.get() as u64;
+
+            // distance to the next compare match
+            let next = self
+                .cc
+                .iter()
+                .map(|cc| cc.get() as u64)
+                .filter(|&cc| cc < period)
+                .map(|cc| match (cc + period - counter) % period {
+                    0 => period,
+                    d => d,
+                })
+                .min()
+                .unwrap_or(period);
+
+            let step = next.min(limit - passed);
+            passed += step;
+            self.total.set(self.total.get() + step);
+            let mut counter = ((counter + step) % period) as u32;
+            if step == next {
+                for n in 0..N {
+                    if self.cc[n].get() == counter {
+                        self.events[n].set(true);
+                        if self.shorts.get() & (1 << n) != 0 {
+                            counter = 0;
+                        }
+                    }
+                }
+            }
+            self.counter.set(counter);
+
+            if stop_on_interrupt && self.interrupt_pending() {
+                break;
+            }
+        }
+        passed
+    }
+}
+
+impl<const N: usize> Simulated for SimTimer<N> {
+    fn advance(&self, ticks: u64) {
+        self.step(ticks, false);
+    }
+
+    fn advance_until_interrupt(&self, limit: u64) -> u64 {
+        self.step(limit, true)
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        (0..N).any(|n| self.events[n].get() && self.inten.get() & (1 << (16 + n)) != 0)
+    }
+}
+
+impl<const N: usize> TimerRegisters for SimTimer<N> {
+    fn task_start(&self) {
+        self.running.set(true);
+    }
+
+    fn task_stop(&self) {
+        self.running.set(false);
+    }
+
+    fn task_clear(&self) {
+        self.counter.set(0);
+    }
+
+    fn configure(&self, bitmode: Bitmode, prescaler: u8) {
+        self.mask.set(match bitmode {
+            Bitmode::Bits16 => 0xFFFF,
+            Bitmode::Bits32 => 0xFFFF_FFFF,
+        });
+        self.prescaler.set(prescaler);
+    }
+
+    fn capture(&self, n: usize) -> u32 {
+        self.step(self.advance_on_capture.get(), false);
+        let counter = self.counter.get();
+        self.cc[n].set(counter);
+        if let Some(f) = self.after_capture.take() {
+            f();
+        }
+        counter
+    }
+
+    fn read_cc(&self, n: usize) -> u32 {
+        self.cc[n].get()
+    }
+
+    fn clear_shorts(&self) {
+        self.shorts.set(0);
+    }
+
+    fn start_task_address(&self) -> u32 {
+        self.base + Self::TASKS_START
+    }
+
+    fn capture_task_address(&self, n: usize) -> u32 {
+        self.base + Self::TASKS_CAPTURE + 4 * n as u32
+    }
+}
+
+impl<const N: usize> CompareRegisters for SimTimer<N> {
+    fn set_cc(&self, n: usize, value: u32) {
+        self.cc[n].set(value);
+    }
+
+    fn is_compare(&self, n: usize) -> bool {
+        self.events[n].get()
+    }
+
+    fn clear_compare(&self, n: usize) {
+        self.events[n].set(false);
+    }
+
+    fn enable_compare_interrupt(&self, n: usize) {
+        self.inten.set(self.inten.get() | 1 << (16 + n));
+    }
+
+    fn disable_compare_interrupt(&self, n: usize) {
+        self.inten.set(self.inten.get() & !(1 << (16 + n)));
+    }
+
+    fn is_compare_interrupt_enabled(&self, n: usize) -> bool {
+        self.inten.get() & (1 << (16 + n)) != 0
+    }
+}
+
+impl<const N: usize> Instance16 for &'static SimTimer<N> {
+    const CC_CHANNELS: usize = N;
+
+    type Registers = SimTimer<N>;
+
+    fn as_timer0(&self) -> &'static SimTimer<N> {
+        self
+    }
+
+    fn shared_period(&self) -> &'static SharedPeriod {
+        &self.period
+    }
+
+    #[cfg(feature = "stats")]
+    fn shared_stats(&self) -> &'static Stats {
+        &self.stats
+    }
+}
+
+impl<const N: usize> Instance32 for &'static SimTimer<N> {}
+
+/// An RTC with `N` capture/compare channels
+///
+/// Like the hardware, a compare register written less than two ticks before the counter
+/// reaches its value does not generate an event.
+pub struct SimRtc<const N: usize> {
+    running: Cell<bool>,
+    time: Cell<u64>,
+    counter: Cell<u32>,
+    prescaler: Cell<u16>,
+    cc: [Cell<u32>; N],
+    cc_written: [Cell<u64>; N],
+    events: [Cell<bool>; N],
+    overflow: Cell<bool>,
+    inten: Cell<u32>,
+    evten: Cell<u32>,
+    routed: Cell<u32>,
+    base: u32,
+    period: SharedPeriod,
+    #[cfg(feature = "stats")]
+    stats: Stats,
+}
+
+impl<const N: usize> SimRtc<N> {
+    const PERIOD: u64 = 1 << 24;
+    const INTEN_OVRFLW: u32 = 1 << 1;
+    const EVENTS_TICK: u32 = 0x100;
+    const EVENTS_COMPARE: u32 = 0x140;
+    const EVTEN_TICK: u32 = 1;
+
+    /// A new RTC, leaked to live as long as the registers of a real one
+    pub fn new() -> &'static Self {
+        Box::leak(Box::new(Self {
+            running: Cell::new(false),
+            time: Cell::new(0),
+            counter: Cell::new(0),
+            prescaler: Cell::new(0),
+            cc: cells(0),
+            cc_written: cells(0),
+            events: cells(false),
+            overflow: Cell::new(false),
+            inten: Cell::new(0),
+            evten: Cell::new(0),
+            routed: Cell::new(0),
+            base: base_address(),
+            period: SharedPeriod::new(),
+            #[cfg(feature = "stats")]
+            stats: Stats::new(),
+        }))
+    }
+
+    pub fn prescaler(&self) -> u16 {
+        self.prescaler.get()
+    }
+
+    /// Addresses of the tick and compare events routed to the PPI during the last `advance`
+    pub fn routed_events(&self) -> impl Iterator<Item = u32> + '_ {
+        let tick = self.routed.get() & Self::EVTEN_TICK != 0;
+        tick.then(|| self.tick_event_address()).into_iter().chain(
+            (0..N)
+                .filter(move |n| self.routed.get() & (1 << (16 + n)) != 0)
+                .map(move |n| self.compare_event_address(n)),
+        )
+    }
+
+    /// Let time pass like `advance_until_interrupt`, but also stop at the first tick that routed
+    /// an event to the PPI
+    pub fn advance_until_routed(&self, limit: u64, stop_on_interrupt: bool) -> u64 {
+        self.step(limit, stop_on_interrupt, true)
+    }
+
+    fn step(&self, limit: u64, stop_on_interrupt: bool, stop_on_route: bool) -> u64 {
+        let mut passed = 0;
+        self.routed.set(0);
+        while passed < limit && self.running.get() {
+            let counter = self.counter.get() as u64;
+
+            // distance to the next compare match or overflow
+            let next = self
+                .cc
+                .iter()
+                .map(
+                    |cc| match (cc.get() as u64 + Self::PERIOD - counter) % Self::PERIOD {
+                        0 => Self::PERIOD,
+                        d => d,
+                    },
+                )
+                .min()
+                .unwrap_or(Self::PERIOD)
+                .min(Self::PERIOD - counter);
+            // every tick is an event while TICK is routed
+            let next = if self.evten.get() & Self::EVTEN_TICK != 0 {
+                1
+            } else {
+                next
+            };
+
+            let step = next.min(limit - passed);
+            passed += step;
+            self.time.set(self.time.get() + step);
+            let counter = ((counter + step) % Self::PERIOD) as u32;
+            if step == next {
+                self.routed
+                    .set(self.routed.get() | (self.evten.get() & Self::EVTEN_TICK));
+                if counter == 0 {
+                    self.overflow.set(true);
+                }
+                for n in 0..N {
+                    if self.cc[n].get() == counter
+                        && self.time.get() - self.cc_written[n].get() >= 2
+                    {
+                        self.events[n].set(true);
+                        self.routed
+                            .set(self.routed.get() | (self.evten.get() & 1 << (16 + n)));
+                    }
+                }
+            }
+            self.counter.set(counter);
+
+            if (stop_on_interrupt && self.interrupt_pending())
+                || (stop_on_route && self.routed.get() != 0)
+            {
+                break;
+            }
+        }
+        passed
+    }
+}
+
+impl<const N: usize> Simulated for SimRtc<N> {
+    fn advance(&self, ticks: u64) {
+        self.step(ticks, false, false);
+    }
+
+    fn advance_until_interrupt(&self, limit: u64) -> u64 {
+        self.step(limit, true, false)
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        let inten = self.inten.get();
+        (self.overflow.get() && inten & Self::INTEN_OVRFLW != 0)
+            || (0..N).any(|n| self.events[n].get() && inten & (1 << (16 + n)) != 0)
+    }
+}
+
+impl<const N: usize> RtcRegisters for SimRtc<N> {
+    fn task_start(&self) {
+        self.running.set(true);
+    }
+
+    fn task_stop(&self) {
+        self.running.set(false);
+    }
+
+    fn task_clear(&self) {
+        self.counter.set(0);
+    }
+
+    fn set_prescaler(&self, prescaler: u16) {
+        assert!(!self.running.get(), "prescaler written while running");
+        self.prescaler.set(prescaler);
+    }
+
+    fn counter(&self) -> u32 {
+        self.counter.get()
+    }
+
+    fn is_overflow(&self) -> bool {
+        self.overflow.get()
+    }
+
+    fn clear_overflow(&self) {
+        self.overflow.set(false);
+    }
+
+    fn enable_overflow_interrupt(&self) {
+        self.inten.set(self.inten.get() | Self::INTEN_OVRFLW);
+    }
+
+    fn enable_compare_event(&self, n: usize) {
+        self.evten.set(self.evten.get() | 1 << (16 + n));
+    }
+
+    fn compare_event_address(&self, n: usize) -> u32 {
+        self.base + Self::EVENTS_COMPARE + 4 * n as u32
+    }
+
+    fn enable_tick_event(&self) {
+        self.evten.set(self.evten.get() | Self::EVTEN_TICK);
+    }
+
+    fn disable_tick_event(&self) {
+        self.evten.set(self.evten.get() & !Self::EVTEN_TICK);
+    }
+
+    fn tick_event_address(&self) -> u32 {
+        self.base + Self::EVENTS_TICK
+    }
+}
+
+impl<const N: usize> CompareRegisters for SimRtc<N> {
+    fn set_cc(&self, n: usize, value: u32) {
+        assert!(value < 1 << 24, "CC is 24 bits wide");
+        self.cc[n].set(value);
+        self.cc_written[n].set(self.time.get());
+    }
+
+    fn is_compare(&self, n: usize) -> bool {
+        self.events[n].get()
+    }
+
+    fn clear_compare(&self, n: usize) {
+        self.events[n].set(false);
+    }
+
+    fn enable_compare_interrupt(&self, n: usize) {
+        self.inten.set(self.inten.get() | 1 << (16 + n));
+    }
+
+    fn disable_compare_interrupt(&self, n: usize) {
+        self.inten.set(self.inten.get() & !(1 << (16 + n)));
+    }
+
+    fn is_compare_interrupt_enabled(&self, n: usize) -> bool {
+        self.inten.get() & (1 << (16 + n)) != 0
+    }
+}
+
+impl<const N: usize> RtcInstance for &'static SimRtc<N> {
+    const CC_CHANNELS: usize = N;
+
+    type Registers = SimRtc<N>;
+
+    fn as_rtc0(&self) -> &'static SimRtc<N> {
+        self
+    }
+
+    fn shared_period(&self) -> &'static SharedPeriod {
+        &self.period
+    }
+
+    #[cfg(feature = "stats")]
+    fn shared_stats(&self) -> &'static Stats {
+        &self.stats
+    }
+}
+
+#[cfg(not(any(
+    feature = "51",
+    feature = "5340-app",
+    feature = "5340-net",
+    feature = "9160"
+)))]
+pub use hybrid::SimHybrid;
+
+#[cfg(not(any(
+    feature = "51",
+    feature = "5340-app",
+    feature = "5340-net",
+    feature = "9160"
+)))]
+mod hybrid {
+    use super::{cells, SimRtc, SimTimer, Simulated};
+    use crate::ppi::PpiRegisters;
+    use core::cell::Cell;
+
+    const CHANNELS: usize = 8;
+
+    /// A PPI with eight channels
+    pub struct SimPpi {
+        channels: [Cell<(u32, u32, u32)>; CHANNELS],
+        enabled: Cell<u32>,
+    }
+
+    impl SimPpi {
+        pub fn new() -> &'static Self {
+            Box::leak(Box::new(Self {
+                channels: cells((0, 0, 0)),
+                enabled: Cell::new(0),
+            }))
+        }
+
+        /// The tasks an occurrence of the event at `eep` triggers
+        fn tasks(&self, eep: u32) -> impl Iterator<Item = u32> + '_ {
+            (0..CHANNELS)
+                .filter(move |&ch| self.enabled.get() & (1 << ch) != 0)
+                .map(move |ch| self.channels[ch].get())
+                .filter(move |&(e, _, _)| e == eep)
+                .flat_map(|(_, tep, fork)| [tep, fork])
+                .filter(|&task| task != 0)
+        }
+    }
+
+    impl PpiRegisters for SimPpi {
+        fn connect(&self, ch: usize, eep: u32, tep: u32, fork: u32) {
+            self.channels[ch].set((eep, tep, fork));
+        }
+
+        fn enable(&self, ch: usize) {
+            self.enabled.set(self.enabled.get() | 1 << ch);
+        }
+    }
+
+    /// An RTC and a TIMER on a common time base, with a PPI between them
+    ///
+    /// Time advances in units of 1/512µs: an RTC tick lasts 15625 units unless the RTC is set to
+    /// drift, a TIMER tick 512 at 1MHz and 32 at 16MHz. The TIMER counts its first tick a full
+    /// TIMER tick after being started.
+    pub struct SimHybrid {
+        pub rtc: &'static SimRtc<4>,
+        pub timer: &'static SimTimer<4>,
+        pub ppi: &'static SimPpi,
+        rtc_tick: Cell<u64>,
+        rtc_phase: Cell<u64>,
+        timer_phase: Cell<u64>,
+    }
+
+    impl SimHybrid {
+        pub const RTC_TICK: u64 = 15_625;
+        pub const TIMER_TICK: u64 = 512;
+
+        pub fn new() -> &'static Self {
+            Box::leak(Box::new(Self {
+                rtc: SimRtc::new(),
+                timer: SimTimer::new(),
+                ppi: SimPpi::new(),
+                rtc_tick: Cell::new(Self::RTC_TICK),
+                rtc_phase: Cell::new(0),
+                timer_phase: Cell::new(0),
+            }))
+        }
+
+        /// Let an RTC tick last `units` instead of `RTC_TICK`, to simulate a drifting LFCLK
+        pub fn set_rtc_tick(&self, units: u64) {
+            self.rtc_tick.set(units);
+        }
+
+        /// Units in a tick of the TIMER at its prescaler
+        fn timer_tick(&self) -> u64 {
+            32 << self.timer.prescaler()
+        }
+
+        /// Trigger the tasks of the events the RTC routed to the PPI
+        fn route(&self) {
+            let running = self.timer.is_running();
+            for event in self.rtc.routed_events() {
+                for task in self.ppi.tasks(event) {
+                    self.timer.trigger(task);
+                }
+            }
+            if !running && self.timer.is_running() {
+                self.timer_phase.set(0);
+            }
+        }
+
+        fn step(&self, limit: u64, stop_on_interrupt: bool) -> u64 {
+            let mut passed = 0;
+            while passed < limit {
+                let rtc_tick = self.rtc_tick.get();
+                let to_rtc = rtc_tick - self.rtc_phase.get();
+
+                // only the RTC runs, let it count up to the next event for the PPI
+                if !self.timer.is_running() && to_rtc <= limit - passed {
+                    let ticks = 1 + (limit - passed - to_rtc) / rtc_tick;
+                    let ticks = self.rtc.advance_until_routed(ticks, stop_on_interrupt);
+                    passed += to_rtc + ticks.saturating_sub(1) * rtc_tick;
+                    self.rtc_phase.set(0);
+                    self.route();
+                } else {
+                    let to_timer = if self.timer.is_running() {
+                        self.timer_tick() - self.timer_phase.get()
+                    } else {
+                        u64::MAX
+                    };
+                    let step = to_rtc.min(to_timer).min(limit - passed);
+                    passed += step;
+
+                    if self.timer.is_running() {
+                        self.timer_phase.set(self.timer_phase.get() + step);
+                        if step == to_timer {
+                            self.timer_phase.set(0);
+                            self.timer.advance(1);
+                        }
+                    }
+                    self.rtc_phase.set(self.rtc_phase.get() + step);
+                    if step == to_rtc {
+                        self.rtc_phase.set(0);
+                        self.rtc.advance(1);
+                        self.route();
+                    }
+                }
+
+                if stop_on_interrupt && self.interrupt_pending() {
+                    break;
+                }
+            }
+            passed
+        }
+    }
+
+    impl Simulated for SimHybrid {
+        fn advance(&self, units: u64) {
+            self.step(units, false);
+        }
+
+        fn advance_until_interrupt(&self, limit: u64) -> u64 {
+            self.step(limit, true)
+        }
+
+        fn interrupt_pending(&self) -> bool {
+            self.rtc.interrupt_pending() || self.timer.interrupt_pending()
+        }
+    }
+}
+
+/// HFCLK and LFCLK status, both clocks are stopped until a test starts them
+#[derive(Default)]
+pub struct SimClock {
+    pub hfxo: Cell<bool>,
+    pub lfclk: Cell<bool>,
+}
+
+impl ClockRegisters for SimClock {
+    fn is_hfxo_running(&self) -> bool {
+        self.hfxo.get()
+    }
+
+    fn is_lfclk_running(&self) -> bool {
+        self.lfclk.get()
+    }
+}
+
+/// Call `on_interrupt` if the simulated peripheral raised its interrupt
+pub fn handle_interrupt<M: Monotonic>(mono: &mut M, sim: &impl Simulated) {
+    if sim.interrupt_pending() {
+        mono.on_interrupt();
+    }
+}
+
+/// Drive `mono` the way the RTIC timer queue does until `instant` is reached
+///
+/// The interrupt handler checks the queue, arms the compare, checks again for a race and lets
+/// the monotonic do its housekeeping. In between, the simulated peripheral runs until it raises
+/// the interrupt again. Returns the time at which the task would be dispatched.
+pub fn wait_until<M: Monotonic>(
+    mono: &mut M,
+    sim: &impl Simulated,
+    instant: M::Instant,
+) -> M::Instant {
+    for _ in 0..1_000 {
+        mono.clear_compare_flag();
+        let now = mono.now();
+        if instant <= now {
+            mono.on_interrupt();
+            return now;
+        }
+        mono.set_compare(instant);
+        let now = mono.now();
+        if instant <= now {
+            mono.on_interrupt();
+            return now;
+        }
+        mono.on_interrupt();
+
+        sim.advance_until_interrupt(u64::MAX);
+    }
+    panic!("the instant was never reached");
+}
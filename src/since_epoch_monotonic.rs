@@ -1,51 +1,119 @@
 // RTIC Monotonic impl for the 32-bit timers
-pub use fugit::{self, ExtU32};
-use nrf52840_hal::pac::{timer0, TIMER0, TIMER1, TIMER2};
+//
+// CC0 is used for the compare and CC1 to capture the current time, all other channels are free.
+use crate::alarm::{CompareRegisters, Split};
+use crate::clocks::{check_hfxo, ClockError, ClockRegisters};
+use crate::timer_instance::{Bitmode, Instance32, TimerRegisters};
+pub use fugit;
 use rtic_monotonic::Monotonic;
 
 pub struct MonoTimer<T: Instance32>(T);
 
 impl<T: Instance32> MonoTimer<T> {
-pub fn new(timer: T) -> Self {
-    timer.prescaler.write(
-        |w| unsafe { w.prescaler().bits(4) }, // 1 MHz
-    );
-    timer.bitmode.write(|w| w.bitmode()._32bit());
-    MonoTimer(timer)
-}
+    /// Configure the TIMER, it is started in `reset`
+    pub fn new(timer: T) -> Self {
+        timer.as_timer0().configure(Bitmode::Bits32, 4); // 1 MHz
+        MonoTimer(timer)
+    }
+
+    /// `new`, after checking that the HFCLK runs from the crystal oscillator
+    ///
+    /// `clock` is the CLOCK peripheral or the `Clocks` of the HAL.
+    pub fn try_new(timer: T, clock: &impl ClockRegisters) -> Result<Self, ClockError> {
+        check_hfxo(clock)?;
+        Ok(Self::new(timer))
+    }
 }
 
 impl<T: Instance32> Monotonic for MonoTimer<T> {
-type Instant = fugit::TimerInstantU32<1_000_000>;
-type Duration = fugit::TimerDurationU32<1_000_000>;
+    type Instant = fugit::TimerInstantU32<1_000_000>;
+    type Duration = fugit::TimerDurationU32<1_000_000>;
 
-unsafe fn reset(&mut self) {
-    self.0.intenset.modify(|_, w| w.compare0().set());
-    self.0.tasks_clear.write(|w| w.bits(1));
-    self.0.tasks_start.write(|w| w.bits(1));
-}
+    unsafe fn reset(&mut self) {
+        let t0 = self.0.as_timer0();
+        for n in 0..T::CC_CHANNELS {
+            t0.reset_channel(n);
+        }
+        t0.enable_compare_interrupt(0);
+        t0.task_clear();
+        t0.task_start();
+    }
 
-#[inline(always)]
-fn now(&mut self) -> Self::Instant {
-    self.0.tasks_capture[1].write(|w| unsafe { w.bits(1) });
-    Self::Instant::from_ticks(self.0.cc[1].read().bits())
-}
+    #[inline(always)]
+    fn now(&mut self) -> Self::Instant {
+        Self::Instant::from_ticks(self.0.as_timer0().capture(1))
+    }
 
-fn set_compare(&mut self, instant: Self::Instant) {
-    self.0.cc[0].write(|w| unsafe { w.cc().bits(instant.duration_since_epoch().ticks()) });
-}
+    fn set_compare(&mut self, instant: Self::Instant) {
+        self.0
+            .as_timer0()
+            .set_cc(0, instant.duration_since_epoch().ticks());
+    }
 
-fn clear_compare_flag(&mut self) {
-    self.0.events_compare[0].write(|w| w);
-}
+    fn clear_compare_flag(&mut self) {
+        self.0.as_timer0().clear_compare(0);
+    }
 
-#[inline(always)]
-fn zero() -> Self::Instant {
-    Self::Instant::from_ticks(0)
+    #[inline(always)]
+    fn zero() -> Self::Instant {
+        Self::Instant::from_ticks(0)
+    }
 }
+
+impl<T: Instance32> Split for MonoTimer<T> {
+    type Registers = T::Registers;
+
+    const HZ: u32 = 1_000_000;
+    const COUNTER_BITS: u32 = 32;
+    const MIN_DISTANCE: u64 = 1;
+
+    #[inline(always)]
+    fn counter(registers: &Self::Registers) -> u32 {
+        registers.capture(1)
+    }
+
+    #[inline(always)]
+    fn ticks(instant: Self::Instant) -> u64 {
+        instant.ticks() as u64
+    }
 }
 
-pub trait Instance32: core::ops::Deref<Target = timer0::RegisterBlock> {}
-impl Instance32 for TIMER0 {}
-impl Instance32 for TIMER1 {}
-impl Instance32 for TIMER2 {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{wait_until, SimClock, SimTimer, Simulated};
+
+    #[test]
+    fn reset_starts_the_timer() {
+        let sim = SimTimer::<4>::new();
+        let mut mono = MonoTimer::new(sim);
+        assert!(!sim.is_running());
+        unsafe { mono.reset() };
+        assert!(sim.is_running());
+    }
+
+    #[test]
+    fn compare_fires_at_instant() {
+        let sim = SimTimer::<4>::new();
+        let mut mono = MonoTimer::new(sim);
+        unsafe { mono.reset() };
+        assert_eq!(sim.prescaler(), 4);
+
+        sim.advance(0xFFFF_FF00);
+        for delay in [1, 1234, 0x100, 0x7FFF_0000] {
+            let instant = mono.now() + fugit::TimerDurationU32::<1_000_000>::from_ticks(delay);
+            assert_eq!(wait_until(&mut mono, sim, instant), instant);
+        }
+    }
+
+    #[test]
+    fn try_new_needs_the_hfxo() {
+        let clock = SimClock::default();
+        clock.lfclk.set(true);
+        let mono = MonoTimer::try_new(SimTimer::<4>::new(), &clock);
+        assert_eq!(mono.err(), Some(ClockError::HfxoNotRunning));
+
+        clock.hfxo.set(true);
+        assert!(MonoTimer::try_new(SimTimer::<4>::new(), &clock).is_ok());
+    }
+}
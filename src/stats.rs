@@ -0,0 +1,221 @@
+//! Event counters of a monotonic, to catch scheduling overload in the field
+//!
+//! Every instance has a `Stats` in a static next to its half-period count. `stats()` of the
+//! monotonic hands it out, it can be read from any context while RTIC owns the monotonic:
+//!
+//! ```ignore
+//! let mono = NrfMonotonic::new(cx.device.TIMER1);
+//! let stats = mono.stats();
+//! // later, from a task
+//! let snapshot = stats.snapshot();
+//! ```
+//!
+//! Only the monotonic writes the counters, from `on_interrupt`, `clear_compare_flag` and
+//! `set_compare`, which RTIC never runs concurrently. They are incremented with plain loads and
+//! stores, as the nRF51 has no atomic read-modify-write instructions.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Counters of a monotonic at one point in time
+///
+/// The counters wrap around, the latency saturates.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct MonotonicStats {
+    /// Half-period and overflow events that extended the time
+    pub overflows: u32,
+    /// Compare events of the timer queue
+    pub compare_hits: u32,
+    /// `set_compare` calls with an instant in the past, the compare is set one tick ahead
+    pub past_deadlines: u32,
+    /// Longest time in ticks from a compare value being reached to `clear_compare_flag`
+    pub max_latency: u32,
+}
+
+/// Event counters of the monotonic running on an instance
+pub struct Stats {
+    overflows: AtomicU32,
+    compare_hits: AtomicU32,
+    past_deadlines: AtomicU32,
+    max_latency: AtomicU32,
+}
+
+#[inline(always)]
+fn increment(counter: &AtomicU32) {
+    counter.store(counter.load(Ordering::Relaxed).wrapping_add(1), Ordering::Relaxed);
+}
+
+impl Stats {
+    pub const fn new() -> Self {
+        Self {
+            overflows: AtomicU32::new(0),
+            compare_hits: AtomicU32::new(0),
+            past_deadlines: AtomicU32::new(0),
+            max_latency: AtomicU32::new(0),
+        }
+    }
+
+    /// Read all counters
+    ///
+    /// The counters are read one after the other, an interrupt in between can make them
+    /// slightly inconsistent.
+    pub fn snapshot(&self) -> MonotonicStats {
+        MonotonicStats {
+            overflows: self.overflows.load(Ordering::Relaxed),
+            compare_hits: self.compare_hits.load(Ordering::Relaxed),
+            past_deadlines: self.past_deadlines.load(Ordering::Relaxed),
+            max_latency: self.max_latency.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reset the worst-case latency, e.g. after it was reported
+    pub fn clear_max_latency(&self) {
+        self.max_latency.store(0, Ordering::Relaxed);
+    }
+
+    /// Reset all counters, called from `reset` of the monotonic
+    pub(crate) fn clear(&self) {
+        self.overflows.store(0, Ordering::Relaxed);
+        self.compare_hits.store(0, Ordering::Relaxed);
+        self.past_deadlines.store(0, Ordering::Relaxed);
+        self.max_latency.store(0, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    pub(crate) fn overflow(&self) {
+        increment(&self.overflows);
+    }
+
+    #[inline(always)]
+    pub(crate) fn past_deadline(&self) {
+        increment(&self.past_deadlines);
+    }
+
+    /// A compare event is handled at `now`, `compare_at` is the pending target of `set_compare`
+    ///
+    /// An event before the target comes from a target more than a counter period away, an event
+    /// without a target from the compare value matching again. Neither counts as a hit.
+    #[inline(always)]
+    pub(crate) fn compare_event(&self, compare_at: &mut Option<u64>, now: u64) {
+        if let Some(latency) = compare_at.and_then(|at| now.checked_sub(at)) {
+            *compare_at = None;
+            increment(&self.compare_hits);
+            let latency = latency.min(u32::MAX as u64) as u32;
+            if latency > self.max_latency.load(Ordering::Relaxed) {
+                self.max_latency.store(latency, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{handle_interrupt, wait_until, SimRtc, SimTimer, Simulated};
+    use crate::{NrfMonotonic, RtcMono};
+    use rtic_monotonic::Monotonic;
+
+    type Timer = NrfMonotonic<&'static SimTimer<4>>;
+    type Duration = <Timer as Monotonic>::Duration;
+
+    #[test]
+    fn counts_overflows_and_past_deadlines() {
+        let sim = SimTimer::<4>::new();
+        let mut mono = Timer::new(sim);
+        let stats = mono.stats();
+        unsafe { mono.reset() };
+
+        for _ in 0..3 {
+            sim.advance(0x8000_0000);
+            handle_interrupt(&mut mono, sim);
+        }
+        let now = mono.now();
+        mono.set_compare(now - Duration::from_ticks(5));
+        mono.set_compare(now + Duration::from_ticks(5));
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.overflows, 3);
+        assert_eq!(snapshot.past_deadlines, 1);
+
+        unsafe { mono.reset() };
+        assert_eq!(stats.snapshot(), MonotonicStats::default());
+    }
+
+    #[test]
+    fn measures_worst_case_latency() {
+        let sim = SimTimer::<4>::new();
+        let mut mono = Timer::new(sim);
+        let stats = mono.stats();
+        unsafe { mono.reset() };
+
+        let at = mono.now() + Duration::from_ticks(100);
+        wait_until(&mut mono, sim, at);
+        assert_eq!(stats.snapshot().compare_hits, 1);
+        assert_eq!(stats.snapshot().max_latency, 0);
+
+        // the interrupt is handled 42 ticks late
+        let at = mono.now() + Duration::from_ticks(100);
+        mono.set_compare(at);
+        sim.advance_until_interrupt(u64::MAX);
+        sim.advance(42);
+        mono.clear_compare_flag();
+        assert_eq!(stats.snapshot().compare_hits, 2);
+        assert_eq!(stats.snapshot().max_latency, 42);
+
+        stats.clear_max_latency();
+        assert_eq!(stats.snapshot().max_latency, 0);
+    }
+
+    #[test]
+    fn rtc_counts() {
+        type Rtc = RtcMono<&'static SimRtc<4>>;
+        let sim = SimRtc::<4>::new();
+        let mut mono = Rtc::new(sim);
+        let stats = mono.stats();
+        unsafe { mono.reset() };
+
+        // overflow and half period, CC0 of `reset` matches at the overflow
+        sim.advance(0x100_0000);
+        mono.clear_compare_flag();
+        handle_interrupt(&mut mono, sim);
+        let now = mono.now();
+        mono.set_compare(now - <Rtc as Monotonic>::Duration::from_ticks(1));
+        sim.advance_until_interrupt(u64::MAX);
+        sim.advance(7);
+        mono.clear_compare_flag();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.overflows, 2);
+        assert_eq!(snapshot.past_deadlines, 1);
+        assert_eq!(snapshot.compare_hits, 1);
+        assert_eq!(snapshot.max_latency, 7);
+    }
+
+    #[test]
+    fn stale_compare_is_not_a_hit() {
+        let sim = SimTimer::<4>::new();
+        let mut mono = Timer::new(sim);
+        let stats = mono.stats();
+        unsafe { mono.reset() };
+
+        let at = mono.now() + Duration::from_ticks(100);
+        wait_until(&mut mono, sim, at);
+
+        // the queue is empty, the compare value matches again after a counter period, following
+        // two half-period interrupts
+        for _ in 0..3 {
+            sim.advance_until_interrupt(u64::MAX);
+            mono.clear_compare_flag();
+            mono.on_interrupt();
+        }
+        assert_eq!(mono.now(), at + Duration::from_ticks(1 << 32));
+        assert_eq!(stats.snapshot().compare_hits, 1);
+        assert_eq!(stats.snapshot().max_latency, 0);
+    }
+}
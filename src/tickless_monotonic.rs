@@ -0,0 +1,236 @@
+use crate::clocks::{check_hfxo, check_lfclk, ClockError, ClockRegisters};
+use crate::rtc_instance::RtcInstance;
+use crate::rtc_monotonic_v2::{RtcClock, RTC_HZ};
+#[cfg(feature = "stats")]
+use crate::stats::Stats;
+use crate::timer_instance::Instance32;
+use crate::timer_monotonic::{NrfMonotonic, TIMER_HZ};
+use rtic_monotonic::Monotonic;
+
+/// `NrfMonotonic` that stops its TIMER while the timer queue is empty
+///
+/// With `DISABLE_INTERRUPT_ON_EMPTY_QUEUE`, RTIC calls `disable_timer` once the last scheduled
+/// task was dispatched and `enable_timer` before it schedules the next one. The TIMER is
+/// stopped in between, which releases its HFCLK request, but its counter is kept: the time it
+/// was stopped for is read from a running `RtcMono` and added when it starts again. In between,
+/// `now()` follows the RTC as well.
+///
+/// ```ignore
+/// let rtc = RtcMono::new(cx.device.RTC1);
+/// let mono = TicklessMonotonic::new(cx.device.TIMER1, rtc.clock());
+/// ```
+///
+/// The `RtcMono` has to run as a monotonic of its own, which keeps its time across counter
+/// overflows. Every stop is measured to an RTC tick, about 30.5µs.
+///
+/// CC0 to CC2 are used like in `NrfMonotonic`, alarms cannot be split off as the counter stops.
+pub struct TicklessMonotonic<INSTANCE: Instance32, RTC: RtcInstance, const HZ: u32 = TIMER_HZ> {
+    mono: NrfMonotonic<INSTANCE, HZ>,
+    clock: RtcClock<RTC>,
+    /// Ticks the TIMER was stopped for in total
+    offset: u64,
+    /// Time and RTC ticks when the TIMER was stopped
+    stopped: Option<(u64, u64)>,
+}
+
+impl<INSTANCE: Instance32, RTC: RtcInstance, const HZ: u32> TicklessMonotonic<INSTANCE, RTC, HZ> {
+    /// Configure the Timer Instance, the time it is stopped for is read from `clock`
+    pub fn new(instance: INSTANCE, clock: RtcClock<RTC>) -> Self {
+        Self {
+            mono: NrfMonotonic::new(instance),
+            clock,
+            offset: 0,
+            stopped: None,
+        }
+    }
+
+    /// `new`, after checking that the HFCLK runs from the crystal oscillator and the LFCLK runs
+    ///
+    /// `clocks` is the CLOCK peripheral or the `Clocks` of the HAL.
+    pub fn try_new(
+        instance: INSTANCE,
+        clock: RtcClock<RTC>,
+        clocks: &impl ClockRegisters,
+    ) -> Result<Self, ClockError> {
+        check_hfxo(clocks)?;
+        check_lfclk(clocks)?;
+        Ok(Self::new(instance, clock))
+    }
+
+    /// Event counters of this monotonic, they can be read while RTIC owns it
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> &'static Stats {
+        self.mono.stats()
+    }
+
+    /// `HZ` ticks in `rtc_ticks`, rounded down
+    #[inline(always)]
+    fn idle_ticks(rtc_ticks: u64) -> u64 {
+        let (rtc_hz, hz) = (RTC_HZ as u64, HZ as u64);
+        rtc_ticks / rtc_hz * hz + rtc_ticks % rtc_hz * hz / rtc_hz
+    }
+}
+
+impl<INSTANCE: Instance32, RTC: RtcInstance, const HZ: u32> Monotonic
+    for TicklessMonotonic<INSTANCE, RTC, HZ>
+{
+    const DISABLE_INTERRUPT_ON_EMPTY_QUEUE: bool = true;
+
+    type Instant = fugit::TimerInstantU64<HZ>;
+    type Duration = fugit::TimerDurationU64<HZ>;
+
+    unsafe fn reset(&mut self) {
+        self.offset = 0;
+        self.stopped = None;
+        self.mono.reset();
+
+        // the TIMER does not count while the queue is empty, the RTC does
+        #[cfg(feature = "defmt-timestamp")]
+        crate::timestamp::set_source::<crate::RtcMono<RTC>>(
+            self.clock.registers(),
+            self.clock.period(),
+        );
+    }
+
+    fn now(&mut self) -> Self::Instant {
+        let ticks = match self.stopped {
+            Some((at, since)) => at + Self::idle_ticks(self.clock.ticks() - since),
+            None => self.offset + self.mono.now().ticks(),
+        };
+        Self::Instant::from_ticks(ticks)
+    }
+
+    fn set_compare(&mut self, instant: Self::Instant) {
+        let ticks = instant.ticks().saturating_sub(self.offset);
+        self.mono
+            .set_compare(fugit::TimerInstantU64::from_ticks(ticks));
+    }
+
+    fn clear_compare_flag(&mut self) {
+        self.mono.clear_compare_flag();
+    }
+
+    fn on_interrupt(&mut self) {
+        self.mono.on_interrupt();
+    }
+
+    fn enable_timer(&mut self) {
+        if let Some((_, since)) = self.stopped.take() {
+            self.offset += Self::idle_ticks(self.clock.ticks() - since);
+            self.mono.resume();
+            debug!("TIMER resumed, offset: {}", self.offset);
+        }
+    }
+
+    fn disable_timer(&mut self) {
+        if self.stopped.is_none() {
+            let at = self.now().ticks();
+            self.stopped = Some((at, self.clock.ticks()));
+            self.mono.pause();
+        }
+    }
+
+    fn zero() -> Self::Instant {
+        Self::Instant::from_ticks(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtc_monotonic_v2::RtcMono;
+    use crate::sim::{handle_interrupt, wait_until, SimClock, SimRtc, SimTimer, Simulated};
+
+    type Rtc = RtcMono<&'static SimRtc<4>>;
+    type Mono = TicklessMonotonic<&'static SimTimer<4>, &'static SimRtc<4>>;
+    type Duration = <Mono as Monotonic>::Duration;
+
+    struct Setup {
+        timer: &'static SimTimer<4>,
+        rtc: &'static SimRtc<4>,
+        rtc_mono: Rtc,
+        mono: Mono,
+    }
+
+    impl Setup {
+        fn new() -> Self {
+            let (timer, rtc) = (SimTimer::new(), SimRtc::new());
+            let mut rtc_mono = RtcMono::new(rtc);
+            unsafe { rtc_mono.reset() };
+            let mut mono = TicklessMonotonic::new(timer, rtc_mono.clock());
+            unsafe { mono.reset() };
+            Self {
+                timer,
+                rtc,
+                rtc_mono,
+                mono,
+            }
+        }
+
+        /// Let `seconds` pass on both clocks
+        fn advance(&mut self, seconds: u64) {
+            for _ in 0..seconds {
+                self.timer.advance(1_000_000);
+                handle_interrupt(&mut self.mono, self.timer);
+                self.rtc.advance(RTC_HZ as u64);
+                handle_interrupt(&mut self.rtc_mono, self.rtc);
+            }
+        }
+    }
+
+    #[test]
+    fn time_passes_while_stopped() {
+        let mut s = Setup::new();
+        s.advance(1);
+        assert_eq!(s.mono.now().ticks(), 1_000_000);
+
+        s.mono.disable_timer();
+        assert!(!s.timer.is_running());
+        s.advance(10);
+        assert_eq!(s.mono.now().ticks(), 11_000_000);
+
+        s.mono.enable_timer();
+        assert!(s.timer.is_running());
+        assert_eq!(s.mono.now().ticks(), 11_000_000);
+        s.advance(1);
+        assert_eq!(s.mono.now().ticks(), 12_000_000);
+    }
+
+    #[test]
+    fn stop_longer_than_the_rtc_counter_period() {
+        let mut s = Setup::new();
+        s.mono.disable_timer();
+        s.advance(1000);
+        s.mono.enable_timer();
+        assert_eq!(s.mono.now().ticks(), 1_000_000_000);
+    }
+
+    #[test]
+    fn compare_after_restart() {
+        let mut s = Setup::new();
+        s.advance(2);
+        s.mono.disable_timer();
+        s.advance(3);
+        s.mono.enable_timer();
+
+        let instant = s.mono.now() + Duration::from_ticks(1234);
+        assert_eq!(wait_until(&mut s.mono, s.timer, instant), instant);
+    }
+
+    #[test]
+    fn try_new_needs_both_clocks() {
+        let rtc_mono = RtcMono::new(SimRtc::<4>::new());
+        let clocks = SimClock::default();
+        clocks.lfclk.set(true);
+        let mono = Mono::try_new(SimTimer::new(), rtc_mono.clock(), &clocks);
+        assert_eq!(mono.err(), Some(ClockError::HfxoNotRunning));
+
+        clocks.hfxo.set(true);
+        clocks.lfclk.set(false);
+        let mono = Mono::try_new(SimTimer::new(), rtc_mono.clock(), &clocks);
+        assert_eq!(mono.err(), Some(ClockError::LfclkNotRunning));
+
+        clocks.lfclk.set(true);
+        assert!(Mono::try_new(SimTimer::new(), rtc_mono.clock(), &clocks).is_ok());
+    }
+}
@@ -0,0 +1,308 @@
+use crate::timer_instance::{prescaler, Bitmode, Instance16, TimerRegisters};
+use crate::timer_monotonic::TIMER_HZ;
+
+use crate::alarm::{handle_alarms, Alarm, CompareRegisters, Split};
+use crate::clocks::{check_hfxo, ClockError, ClockRegisters};
+use crate::half_period::calc_now;
+#[cfg(feature = "stats")]
+use crate::stats::Stats;
+use rtic_monotonic::Monotonic;
+
+/// Channels left for alarms on a TIMER with eight channels
+const MAX_ALARMS: usize = 5;
+
+/// Monotonic Timer based on a NRF Timer Instance in 16-bit mode
+///
+/// Every TIMER instance supports 16-bit mode, including TIMER1 and TIMER2 of the nRF51 which
+/// cannot count with 32 bits. The counter is extended to 64 bits in software the same way as in
+/// `NrfMonotonic`: a compare event on CC2, alternating between the middle and the start of the
+/// counter range, increments `period`.
+///
+/// # Interrupt latency
+///
+/// `now()` stays monotonic as long as the half-period interrupt is handled within half a counter
+/// period, i.e. the monotonic's interrupt must not be masked or delayed for 2^15 ticks or longer.
+/// That is about 32 milliseconds at 1MHz and one second at 31.25kHz.
+///
+/// The half-period count is 64 bits wide, so `now()` does not wrap, even though 2^32 half
+/// periods pass in 101 days at 16MHz.
+///
+/// CC0 to CC2 are used by the monotonic, the remaining channels can be split off as alarms.
+pub struct NrfMonotonic16<INSTANCE: Instance16, const HZ: u32 = TIMER_HZ> {
+    timer: INSTANCE,
+    period: u64,
+    callbacks: [Option<fn()>; MAX_ALARMS],
+    /// Target of the last `set_compare` until it is hit
+    #[cfg(feature = "stats")]
+    compare_at: Option<u64>,
+}
+
+impl<INSTANCE: Instance16, const HZ: u32> NrfMonotonic16<INSTANCE, HZ> {
+    /// Prescaler for `HZ`, fails to compile if `HZ` is not 16MHz / 2^n with n in 0..=9
+    const PRESCALER: u8 = prescaler(HZ);
+    const HALF_PERIOD: u32 = 0x8000;
+    const COUNTER_MASK: u64 = 0xFFFF;
+    const CC_COMPARE: usize = 0;
+    const CC_NOW: usize = 1;
+    const CC_PERIOD: usize = 2;
+    const FIRST_ALARM: usize = 3;
+
+    /// Configure the Timer Instance and provide a new `Monotonic` based on this timer
+    /// This Monotonic timer runs at `HZ`, which has to be 16MHz / 2^n with n in 0..=9
+    pub fn new(instance: INSTANCE) -> Self {
+        {
+            let t0 = instance.as_timer0();
+            t0.task_stop();
+            t0.configure(Bitmode::Bits16, Self::PRESCALER);
+            t0.task_clear();
+            t0.task_start();
+        }
+        // The counter runs from here on for `Delay`, `reset` clears it again.
+        NrfMonotonic16 {
+            timer: instance,
+            period: 0,
+            callbacks: [None; MAX_ALARMS],
+            #[cfg(feature = "stats")]
+            compare_at: None,
+        }
+    }
+
+    /// `new`, after checking that the HFCLK runs from the crystal oscillator
+    ///
+    /// `clock` is the CLOCK peripheral or the `Clocks` of the HAL.
+    pub fn try_new(instance: INSTANCE, clock: &impl ClockRegisters) -> Result<Self, ClockError> {
+        check_hfxo(clock)?;
+        Ok(Self::new(instance))
+    }
+
+    /// Hand out the free channels from CC3 on as alarms, calling `callbacks[i]` from
+    /// `on_interrupt` when alarm `i` expires
+    ///
+    /// Panics if the instance has less than `N` free channels.
+    pub fn split<const N: usize>(mut self, callbacks: [fn(); N]) -> (Self, [Alarm<Self>; N]) {
+        assert!(
+            Self::FIRST_ALARM + N <= INSTANCE::CC_CHANNELS,
+            "not enough free channels for the alarms"
+        );
+        for (slot, callback) in self.callbacks.iter_mut().zip(callbacks) {
+            *slot = Some(callback);
+        }
+        let registers = self.timer.as_timer0();
+        let period = self.timer.shared_period();
+        let alarms = core::array::from_fn(|i| Alarm::new(registers, period, Self::FIRST_ALARM + i));
+        (self, alarms)
+    }
+
+    /// Event counters of this monotonic, they can be read while RTIC owns it
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> &'static Stats {
+        self.timer.shared_stats()
+    }
+
+    #[inline(always)]
+    fn is_next_period(&self) -> bool {
+        self.timer.as_timer0().is_compare(Self::CC_PERIOD)
+    }
+
+    #[inline(always)]
+    fn clear_next_period_flag(&self) {
+        self.timer.as_timer0().clear_compare(Self::CC_PERIOD);
+    }
+
+    /// Counter value at which the half period following `period` starts
+    #[inline(always)]
+    fn next_period_start(period: u64) -> u32 {
+        if period & 1 == 0 {
+            Self::HALF_PERIOD
+        } else {
+            0
+        }
+    }
+}
+
+impl<INSTANCE: Instance16, const HZ: u32> Monotonic for NrfMonotonic16<INSTANCE, HZ> {
+    const DISABLE_INTERRUPT_ON_EMPTY_QUEUE: bool = false;
+
+    type Instant = fugit::TimerInstantU64<HZ>;
+    type Duration = fugit::TimerDurationU64<HZ>;
+
+    unsafe fn reset(&mut self) {
+        self.period = 0;
+        self.timer.shared_period().set(0);
+        #[cfg(feature = "stats")]
+        {
+            self.compare_at = None;
+            self.timer.shared_stats().clear();
+        }
+        {
+            let t0 = self.timer.as_timer0();
+            t0.task_stop();
+            t0.task_clear();
+
+            // clear events, compare registers and interrupts of all channels
+            for n in 0..INSTANCE::CC_CHANNELS {
+                t0.reset_channel(n);
+            }
+
+            // prepare compare registers
+            t0.set_cc(Self::CC_PERIOD, Self::next_period_start(0));
+
+            // the counter runs freely, time is extended in `on_interrupt`
+            t0.clear_shorts();
+
+            // enable compare match and overflow interrupts
+            t0.enable_compare_interrupt(Self::CC_COMPARE);
+            t0.enable_compare_interrupt(Self::CC_PERIOD);
+
+            t0.task_start();
+        }
+
+        #[cfg(feature = "defmt-timestamp")]
+        crate::timestamp::set_source::<Self>(self.timer.as_timer0(), self.timer.shared_period());
+    }
+
+    fn now(&mut self) -> Self::Instant {
+        let cnt = self.timer.as_timer0().capture(Self::CC_NOW);
+        Self::Instant::from_ticks(calc_now::<16>(self.period, cnt))
+    }
+
+    fn set_compare(&mut self, val: Self::Instant) {
+        let now = self.now();
+
+        let dur = match val.checked_duration_since(now) {
+            None => {
+                // in the past
+                #[cfg(feature = "stats")]
+                self.timer.shared_stats().past_deadline();
+                1
+            }
+            Some(x) => x.ticks().max(1),
+        };
+
+        // Only the lower 16 bits can be compared. Targets further away than a counter period
+        // fire early, the timer queue will then set the compare value again.
+        #[cfg(feature = "stats")]
+        {
+            self.compare_at = Some(now.ticks() + dur);
+        }
+        let target = (now.ticks() + dur) & Self::COUNTER_MASK;
+        self.timer
+            .as_timer0()
+            .set_cc(Self::CC_COMPARE, target as u32);
+    }
+
+    fn clear_compare_flag(&mut self) {
+        #[cfg(feature = "stats")]
+        if self.timer.as_timer0().is_compare(Self::CC_COMPARE) {
+            let now = self.now().ticks();
+            self.timer
+                .shared_stats()
+                .compare_event(&mut self.compare_at, now);
+        }
+        self.timer.as_timer0().clear_compare(Self::CC_COMPARE);
+    }
+
+    fn on_interrupt(&mut self) {
+        if self.is_next_period() {
+            self.clear_next_period_flag();
+            self.period += 1;
+            self.timer.shared_period().set(self.period);
+            self.timer
+                .as_timer0()
+                .set_cc(Self::CC_PERIOD, Self::next_period_start(self.period));
+            #[cfg(feature = "stats")]
+            self.timer.shared_stats().overflow();
+            debug!("Overflow, period: {}", self.period);
+        }
+
+        handle_alarms(self.timer.as_timer0(), Self::FIRST_ALARM, &self.callbacks);
+    }
+
+    fn zero() -> Self::Instant {
+        Self::Instant::from_ticks(0)
+    }
+}
+
+impl<INSTANCE: Instance16, const HZ: u32> Split for NrfMonotonic16<INSTANCE, HZ> {
+    type Registers = INSTANCE::Registers;
+
+    const HZ: u32 = HZ;
+    const COUNTER_BITS: u32 = 16;
+    const MIN_DISTANCE: u64 = 1;
+
+    #[inline(always)]
+    fn counter(registers: &Self::Registers) -> u32 {
+        registers.capture(Self::CC_NOW)
+    }
+
+    #[inline(always)]
+    fn ticks(instant: Self::Instant) -> u64 {
+        instant.ticks()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{handle_interrupt, wait_until, SimTimer, Simulated};
+
+    type Mono = NrfMonotonic16<&'static SimTimer<4>>;
+
+    fn setup(sim: &'static SimTimer<4>) -> Mono {
+        let mut mono = NrfMonotonic16::new(sim);
+        unsafe { mono.reset() };
+        mono
+    }
+
+    #[test]
+    fn now_past_2_pow_32_half_periods() {
+        let sim = SimTimer::new();
+        let mut mono = setup(sim);
+        sim.advance(0x8000);
+        mono.on_interrupt();
+
+        // jump to the last half period before a 32-bit count wraps, it is odd like the real one
+        mono.period = u32::MAX as u64;
+        let start = mono.now().ticks();
+        assert_eq!(start, (u32::MAX as u64) << 15);
+
+        let mut last = start;
+        for _ in 0..4 {
+            sim.advance(0x6000);
+            handle_interrupt(&mut mono, sim);
+            let now = mono.now().ticks();
+            assert!(now > last);
+            last = now;
+        }
+        assert_eq!(last, start + 4 * 0x6000);
+        assert_eq!(sim.shared_period().get(), (1 << 32) + 2);
+    }
+
+    #[test]
+    fn now_tolerates_delayed_interrupt() {
+        let sim = SimTimer::new();
+        let mut mono = setup(sim);
+        let half = 0x8000;
+        let mut elapsed = 0;
+        for _ in 0..6 {
+            // run into the next half period, then handle its interrupt almost half a period late
+            for step in [half - elapsed % half, half - 1] {
+                sim.advance(step);
+                elapsed += step;
+                assert_eq!(mono.now().ticks(), elapsed);
+            }
+            handle_interrupt(&mut mono, sim);
+            assert_eq!(mono.now().ticks(), elapsed);
+        }
+    }
+
+    #[test]
+    fn compare_fires_at_instant() {
+        let sim = SimTimer::new();
+        let mut mono = setup(sim);
+        for delay in [1, 2, 1234, 0x7FFF, 0x8001, 0xFFFF, 0x1_0000, 300_017] {
+            let instant = mono.now() + <Mono as Monotonic>::Duration::from_ticks(delay);
+            assert_eq!(wait_until(&mut mono, sim, instant), instant);
+        }
+    }
+}
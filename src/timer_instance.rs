@@ -0,0 +1,293 @@
+//! TIMER instances usable by the monotonics of this crate
+//!
+//! On the nRF52, TIMER0 to TIMER2 have four capture/compare channels, TIMER3 and TIMER4 have
+//! six. The nRF5340 application core and the nRF9160 have six channels on every TIMER, the
+//! nRF5340 network core eight.
+//! The monotonics only claim the lower channels, the remaining ones are free.
+
+use crate::alarm::{CompareRegisters, SharedPeriod};
+use crate::hal;
+#[cfg(feature = "stats")]
+use crate::stats::Stats;
+
+#[cfg(not(any(feature = "5340-app", feature = "5340-net", feature = "9160")))]
+use hal::pac::timer0;
+#[cfg(any(feature = "5340-app", feature = "5340-net", feature = "9160"))]
+use hal::pac::timer0_ns as timer0;
+
+pub(crate) use timer0::RegisterBlock as TimerRegister;
+use timer0::{CC, EVENTS_COMPARE, TASKS_CAPTURE};
+
+#[cfg(not(any(feature = "5340-app", feature = "5340-net", feature = "9160")))]
+use hal::pac::{TIMER0, TIMER1, TIMER2};
+
+#[cfg(feature = "52820")]
+use hal::pac::TIMER3;
+
+#[cfg(any(feature = "52832", feature = "52833", feature = "52840"))]
+use hal::pac::{TIMER3, TIMER4};
+
+#[cfg(any(feature = "5340-app", feature = "5340-net", feature = "9160"))]
+use hal::pac::{TIMER0_NS, TIMER1_NS, TIMER2_NS};
+
+#[cfg(any(feature = "5340-app", feature = "9160"))]
+use hal::pac::{TIMER0_S, TIMER1_S, TIMER2_S};
+
+/// Base frequency of the TIMER peripheral, the prescaler divides it by 2^n
+pub const TIMER_BASE_HZ: u32 = 16_000_000;
+const MAX_PRESCALER: u8 = 9;
+
+/// Prescaler for `hz`, panics if `hz` is not 16MHz / 2^n with n in 0..=9
+///
+/// Used to initialize associated constants, so an invalid rate fails to compile.
+pub(crate) const fn prescaler(hz: u32) -> u8 {
+    let mut prescaler = 0;
+    while prescaler < MAX_PRESCALER && TIMER_BASE_HZ >> prescaler != hz {
+        prescaler += 1;
+    }
+    assert!(
+        TIMER_BASE_HZ >> prescaler == hz,
+        "HZ must be 16MHz / 2^n with n in 0..=9"
+    );
+    prescaler
+}
+
+/// Counter width of a TIMER
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bitmode {
+    Bits16,
+    Bits32,
+}
+
+/// Register level access to a TIMER
+///
+/// The monotonics only touch their TIMER through this trait. It is implemented for the PAC
+/// register block and can be implemented by a simulated TIMER to test the monotonics on the host.
+/// Channel numbers must be below the `CC_CHANNELS` of the instance the registers belong to.
+pub trait TimerRegisters: CompareRegisters {
+    fn task_start(&self);
+    fn task_stop(&self);
+    fn task_clear(&self);
+    /// Select timer mode with the given counter width and prescaler
+    fn configure(&self, bitmode: Bitmode, prescaler: u8);
+    /// Capture the counter into channel `n` and return the captured value
+    fn capture(&self, n: usize) -> u32;
+    /// The value of capture/compare register `n`, without capturing
+    fn read_cc(&self, n: usize) -> u32;
+    fn clear_shorts(&self);
+    /// Address of the START task, to be triggered through the PPI
+    fn start_task_address(&self) -> u32;
+    /// Address of the CAPTURE\[n\] task, to be triggered through the PPI
+    fn capture_task_address(&self, n: usize) -> u32;
+
+    /// Clear the compare register, event and interrupt of channel `n`
+    fn reset_channel(&self, n: usize) {
+        self.disable_compare_interrupt(n);
+        self.set_cc(n, 0);
+        self.clear_compare(n);
+    }
+}
+
+/// The TIMER0 register block of the nRF52 only describes four channels. The registers of the
+/// fifth and sixth channel of TIMER3/TIMER4 directly follow the first four, so they are reached
+/// by offsetting into the register arrays.
+impl TimerRegisters for TimerRegister {
+    #[inline(always)]
+    fn task_start(&self) {
+        self.tasks_start.write(|w| unsafe { w.bits(1) });
+    }
+
+    #[inline(always)]
+    fn task_stop(&self) {
+        self.tasks_stop.write(|w| unsafe { w.bits(1) });
+    }
+
+    #[inline(always)]
+    fn task_clear(&self) {
+        self.tasks_clear.write(|w| unsafe { w.bits(1) });
+    }
+
+    fn configure(&self, bitmode: Bitmode, prescaler: u8) {
+        self.mode.write(|w| w.mode().timer());
+        match bitmode {
+            Bitmode::Bits16 => self.bitmode.write(|w| w.bitmode()._16bit()),
+            Bitmode::Bits32 => self.bitmode.write(|w| w.bitmode()._32bit()),
+        }
+        self.prescaler
+            .write(|w| unsafe { w.prescaler().bits(prescaler) });
+    }
+
+    #[inline(always)]
+    fn capture(&self, n: usize) -> u32 {
+        tasks_capture(self, n).write(|w| unsafe { w.bits(1) });
+        cc(self, n).read().bits()
+    }
+
+    #[inline(always)]
+    fn read_cc(&self, n: usize) -> u32 {
+        cc(self, n).read().bits()
+    }
+
+    #[inline(always)]
+    fn clear_shorts(&self) {
+        self.shorts.reset();
+    }
+
+    #[inline(always)]
+    fn start_task_address(&self) -> u32 {
+        &self.tasks_start as *const _ as u32
+    }
+
+    #[inline(always)]
+    fn capture_task_address(&self, n: usize) -> u32 {
+        tasks_capture(self, n) as *const _ as u32
+    }
+}
+
+impl CompareRegisters for TimerRegister {
+    #[inline(always)]
+    fn set_cc(&self, n: usize, value: u32) {
+        cc(self, n).write(|w| unsafe { w.bits(value) });
+    }
+
+    #[inline(always)]
+    fn is_compare(&self, n: usize) -> bool {
+        events_compare(self, n).read().bits() != 0
+    }
+
+    #[inline(always)]
+    fn clear_compare(&self, n: usize) {
+        events_compare(self, n).reset();
+    }
+
+    #[inline(always)]
+    fn enable_compare_interrupt(&self, n: usize) {
+        self.intenset.write(|w| unsafe { w.bits(1 << (16 + n)) });
+    }
+
+    #[inline(always)]
+    fn disable_compare_interrupt(&self, n: usize) {
+        self.intenclr.write(|w| unsafe { w.bits(1 << (16 + n)) });
+    }
+
+    #[inline(always)]
+    fn is_compare_interrupt_enabled(&self, n: usize) -> bool {
+        self.intenset.read().bits() & (1 << (16 + n)) != 0
+    }
+}
+
+#[inline(always)]
+fn cc(t0: &TimerRegister, n: usize) -> &CC {
+    // SAFETY: the caller keeps `n` below the channel count of the instance
+    unsafe { &*t0.cc.as_ptr().add(n) }
+}
+
+#[inline(always)]
+fn events_compare(t0: &TimerRegister, n: usize) -> &EVENTS_COMPARE {
+    // SAFETY: the caller keeps `n` below the channel count of the instance
+    unsafe { &*t0.events_compare.as_ptr().add(n) }
+}
+
+#[inline(always)]
+fn tasks_capture(t0: &TimerRegister, n: usize) -> &TASKS_CAPTURE {
+    // SAFETY: the caller keeps `n` below the channel count of the instance
+    unsafe { &*t0.tasks_capture.as_ptr().add(n) }
+}
+
+/// A TIMER instance, every instance can run in 16-bit mode
+pub trait Instance16 {
+    /// Number of capture/compare channels of this instance
+    const CC_CHANNELS: usize;
+
+    type Registers: TimerRegisters + 'static;
+
+    /// The registers of this instance, the layout is shared by all TIMER instances up to the
+    /// number of capture/compare channels
+    fn as_timer0(&self) -> &'static Self::Registers;
+
+    /// The half-period count of the monotonic running on this instance
+    fn shared_period(&self) -> &'static SharedPeriod;
+
+    /// The event counters of the monotonic running on this instance
+    #[cfg(feature = "stats")]
+    fn shared_stats(&self) -> &'static Stats;
+}
+
+/// A TIMER instance that can run in 32-bit mode
+pub trait Instance32: Instance16 {}
+
+macro_rules! impl_instance {
+    ($channels:expr => $($name:ident,)*) => {
+        $(
+            impl Instance16 for $name {
+                const CC_CHANNELS: usize = $channels;
+
+                type Registers = TimerRegister;
+
+                #[inline(always)]
+                fn as_timer0(&self) -> &'static TimerRegister {
+                    // SAFETY: all TIMER register blocks share their layout, the extra channels
+                    // of larger instances are reached by offsetting into the register arrays
+                    unsafe { &*$name::ptr().cast() }
+                }
+
+                #[inline(always)]
+                fn shared_period(&self) -> &'static SharedPeriod {
+                    static PERIOD: SharedPeriod = SharedPeriod::new();
+                    &PERIOD
+                }
+
+                #[cfg(feature = "stats")]
+                #[inline(always)]
+                fn shared_stats(&self) -> &'static Stats {
+                    static STATS: Stats = Stats::new();
+                    &STATS
+                }
+            }
+        )*
+    }
+}
+
+macro_rules! impl_instance32 {
+    ($($name:ident,)*) => {
+        $(
+            impl Instance32 for $name {}
+        )*
+    }
+}
+
+#[cfg(not(any(feature = "5340-app", feature = "5340-net", feature = "9160")))]
+impl_instance!(4 => TIMER0, TIMER1, TIMER2,);
+
+// the nRF51 TIMERs are limited to 16 or 24 bits
+#[cfg(not(any(
+    feature = "51",
+    feature = "5340-app",
+    feature = "5340-net",
+    feature = "9160"
+)))]
+impl_instance32!(TIMER0, TIMER1, TIMER2,);
+
+#[cfg(feature = "52820")]
+impl_instance!(6 => TIMER3,);
+
+#[cfg(feature = "52820")]
+impl_instance32!(TIMER3,);
+
+#[cfg(any(feature = "52832", feature = "52833", feature = "52840"))]
+impl_instance!(6 => TIMER3, TIMER4,);
+
+#[cfg(any(feature = "52832", feature = "52833", feature = "52840"))]
+impl_instance32!(TIMER3, TIMER4,);
+
+#[cfg(any(feature = "5340-app", feature = "9160"))]
+impl_instance!(6 => TIMER0_NS, TIMER1_NS, TIMER2_NS, TIMER0_S, TIMER1_S, TIMER2_S,);
+
+#[cfg(any(feature = "5340-app", feature = "9160"))]
+impl_instance32!(TIMER0_NS, TIMER1_NS, TIMER2_NS, TIMER0_S, TIMER1_S, TIMER2_S,);
+
+#[cfg(feature = "5340-net")]
+impl_instance!(8 => TIMER0_NS, TIMER1_NS, TIMER2_NS,);
+
+#[cfg(feature = "5340-net")]
+impl_instance32!(TIMER0_NS, TIMER1_NS, TIMER2_NS,);
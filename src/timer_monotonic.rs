@@ -1,32 +1,55 @@
-/// Monotonic Timer based on NRF Timer Instance
-///
-/// The frequency is fixed at 1MHz
-use crate::hal;
-use hal::{pac::timer0::RegisterBlock as TimerRegister, timer::Instance};
+use crate::timer_instance::{prescaler, Bitmode, Instance32, TimerRegisters};
+
+use crate::alarm::{handle_alarms, Alarm, CompareRegisters, Split};
+use crate::clocks::{check_hfxo, ClockError, ClockRegisters};
+use crate::half_period::calc_now;
+#[cfg(feature = "stats")]
+use crate::stats::Stats;
 use rtic_monotonic::Monotonic;
 pub const TIMER_HZ: u32 = 1_000_000;
 
-pub struct NrfMonotonic<INSTANCE: Instance> {
+/// Channels left for alarms on a TIMER with eight channels
+const MAX_ALARMS: usize = 5;
+
+/// Monotonic Timer based on NRF Timer Instance
+///
+/// The tick rate is selected with the `HZ` const generic and defaults to 1MHz. The 32-bit
+/// counter runs freely and is extended to 64 bits by counting half periods: a compare event on
+/// CC2, alternating between the middle and the start of the counter range, increments `period`.
+///
+/// # Interrupt latency
+///
+/// `now()` stays monotonic as long as the half-period interrupt is handled within half a counter
+/// period, i.e. the monotonic's interrupt must not be masked or delayed for 2^31 ticks or longer.
+/// That is about 35 minutes at 1MHz and 134 seconds at 16MHz.
+///
+/// CC0 to CC2 are used by the monotonic, CC3 (and CC4/CC5 on TIMER3/TIMER4) can be split off
+/// as alarms.
+pub struct NrfMonotonic<INSTANCE: Instance32, const HZ: u32 = TIMER_HZ> {
     timer: INSTANCE,
-    ovf: u64,
+    period: u64,
+    callbacks: [Option<fn()>; MAX_ALARMS],
+    /// Target of the last `set_compare` until it is hit
+    #[cfg(feature = "stats")]
+    compare_at: Option<u64>,
 }
 
-impl<INSTANCE: Instance> NrfMonotonic<INSTANCE> {
-    const OVFLOW_REGISTER: u32 = u32::MAX >> 1;
-    const OVFLOW_INCREMENT: u64 = (Self::OVFLOW_REGISTER as u64) + 1;
-    /// Enable the Timer Instance and provide a new `Monotonic` based on this timer
-    /// This Monotonic timer is fixed at 1MHz
+impl<INSTANCE: Instance32, const HZ: u32> NrfMonotonic<INSTANCE, HZ> {
+    /// Prescaler for `HZ`, fails to compile if `HZ` is not 16MHz / 2^n with n in 0..=9
+    const PRESCALER: u8 = prescaler(HZ);
+    const HALF_PERIOD: u32 = 0x8000_0000;
     const CC_COMPARE: usize = 0;
     const CC_NOW: usize = 1;
-    const CC_OVERFLOW: usize = 2;
+    const CC_PERIOD: usize = 2;
+    const FIRST_ALARM: usize = 3;
+    /// Enable the Timer Instance and provide a new `Monotonic` based on this timer
+    /// This Monotonic timer runs at `HZ`, which has to be 16MHz / 2^n with n in 0..=9
     pub fn new(instance: INSTANCE) -> Self {
         {
             // set up the peripheral
             let t0 = instance.as_timer0();
 
-            t0.mode.write(|w| w.mode().timer());
-            t0.bitmode.write(|w| w.bitmode()._32bit());
-            t0.prescaler.write(|w| unsafe { w.prescaler().bits(4) });
+            t0.configure(Bitmode::Bits32, Self::PRESCALER);
 
             start_timer0(t0);
         }
@@ -34,103 +57,173 @@ impl<INSTANCE: Instance> NrfMonotonic<INSTANCE> {
         // We do not start the counter here, it is started in `reset`.
         NrfMonotonic {
             timer: instance,
-            ovf: 0,
+            period: 0,
+            callbacks: [None; MAX_ALARMS],
+            #[cfg(feature = "stats")]
+            compare_at: None,
+        }
+    }
+
+    /// `new`, after checking that the HFCLK runs from the crystal oscillator
+    ///
+    /// `clock` is the CLOCK peripheral or the `Clocks` of the HAL.
+    pub fn try_new(instance: INSTANCE, clock: &impl ClockRegisters) -> Result<Self, ClockError> {
+        check_hfxo(clock)?;
+        Ok(Self::new(instance))
+    }
+
+    /// Hand out the free channels from CC3 on as alarms, calling `callbacks[i]` from
+    /// `on_interrupt` when alarm `i` expires
+    ///
+    /// Panics if the instance has less than `N` free channels.
+    pub fn split<const N: usize>(mut self, callbacks: [fn(); N]) -> (Self, [Alarm<Self>; N]) {
+        assert!(
+            Self::FIRST_ALARM + N <= INSTANCE::CC_CHANNELS,
+            "not enough free channels for the alarms"
+        );
+        for (slot, callback) in self.callbacks.iter_mut().zip(callbacks) {
+            *slot = Some(callback);
         }
+        let registers = self.timer.as_timer0();
+        let period = self.timer.shared_period();
+        let alarms = core::array::from_fn(|i| Alarm::new(registers, period, Self::FIRST_ALARM + i));
+        (self, alarms)
+    }
+
+    /// Event counters of this monotonic, they can be read while RTIC owns it
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> &'static Stats {
+        self.timer.shared_stats()
     }
 
     #[inline(always)]
-    fn is_overflow(&self) -> bool {
-        self.timer.as_timer0().events_compare[Self::CC_OVERFLOW]
-            .read()
-            .events_compare()
-            .bit()
+    fn is_next_period(&self) -> bool {
+        self.timer.as_timer0().is_compare(Self::CC_PERIOD)
     }
 
     #[inline(always)]
     fn is_compare_match(&self) -> bool {
-        self.timer.as_timer0().events_compare[Self::CC_COMPARE]
-            .read()
-            .events_compare()
-            .bit()
+        self.timer.as_timer0().is_compare(Self::CC_COMPARE)
     }
 
     #[inline(always)]
-    fn clear_overflow_flag(&self) {
-        self.timer.as_timer0().events_compare[Self::CC_OVERFLOW]
-            .write(|w| w.events_compare().clear_bit());
+    fn clear_next_period_flag(&self) {
+        self.timer.as_timer0().clear_compare(Self::CC_PERIOD);
+    }
+
+    /// Counter value at which the half period following `period` starts
+    #[inline(always)]
+    fn next_period_start(period: u64) -> u32 {
+        if period & 1 == 0 {
+            Self::HALF_PERIOD
+        } else {
+            0
+        }
     }
 
     #[inline(always)]
     fn clear_compare_match_flag(&self) {
-        self.timer.as_timer0().events_compare[Self::CC_COMPARE]
-            .write(|w| w.events_compare().clear_bit());
+        self.timer.as_timer0().clear_compare(Self::CC_COMPARE);
+    }
+
+    /// Stop the counter without clearing it, `resume` continues from the same value
+    #[inline(always)]
+    pub(crate) fn pause(&self) {
+        self.timer.as_timer0().task_stop();
+    }
+
+    #[inline(always)]
+    pub(crate) fn resume(&self) {
+        self.timer.as_timer0().task_start();
     }
 }
 
 #[inline(always)]
-fn start_timer0(t0: &TimerRegister) {
-    t0.tasks_stop.write(|w| w.tasks_stop().set_bit());
-    t0.tasks_clear.write(|w| w.tasks_clear().set_bit());
+fn start_timer0(t0: &impl TimerRegisters) {
+    t0.task_stop();
+    t0.task_clear();
     enable_interrupts(t0);
-    t0.tasks_start.write(|w| w.tasks_start().set_bit());
+    t0.task_start();
 }
 
 #[inline(always)]
-fn stop_timer0(t0: &TimerRegister) {
+fn stop_timer0(t0: &impl TimerRegisters) {
     disable_interrupts(t0);
-    t0.tasks_stop.write(|w| w.tasks_stop().set_bit());
+    t0.task_stop();
 }
 
-impl<INSTANCE: Instance> Monotonic for NrfMonotonic<INSTANCE> {
+impl<INSTANCE: Instance32, const HZ: u32> Monotonic for NrfMonotonic<INSTANCE, HZ> {
     const DISABLE_INTERRUPT_ON_EMPTY_QUEUE: bool = false;
 
     unsafe fn reset(&mut self) {
-        self.ovf = 0;
+        self.period = 0;
+        self.timer.shared_period().set(0);
+        #[cfg(feature = "stats")]
+        {
+            self.compare_at = None;
+            self.timer.shared_stats().clear();
+        }
         {
             let t0 = self.timer.as_timer0();
-            t0.tasks_stop.write(|w| w.tasks_stop().set_bit());
-            t0.tasks_clear.write(|w| w.tasks_clear().set_bit());
+            t0.task_stop();
+            t0.task_clear();
 
-            // clear events
-            t0.events_compare[0].write(|w| w.events_compare().clear_bit());
-            t0.events_compare[1].write(|w| w.events_compare().clear_bit());
-            t0.events_compare[2].write(|w| w.events_compare().clear_bit());
-            t0.events_compare[3].write(|w| w.events_compare().clear_bit());
+            // clear events, compare registers and interrupts of all channels
+            for n in 0..INSTANCE::CC_CHANNELS {
+                t0.reset_channel(n);
+            }
 
             // prepare compare registers
-            t0.cc[0].reset();
-            t0.cc[1].reset();
-            t0.cc[2].write(|w| unsafe { w.bits(Self::OVFLOW_REGISTER) }); // so we have an explicit overflow
-            t0.cc[3].reset();
+            t0.set_cc(Self::CC_PERIOD, Self::next_period_start(0));
 
-            t0.shorts.write(|w| w.compare2_clear().set_bit());
-
-            // disable unneeded interrupts
-            disable_interrupts(t0);
+            // the counter runs freely, time is extended in `on_interrupt`
+            t0.clear_shorts();
 
             // enable copmare match and overflow interrupts
             enable_interrupts(t0);
 
             // start the timer
-            t0.tasks_start.write(|w| w.tasks_start().set_bit());
+            t0.task_start();
         }
+
+        #[cfg(feature = "defmt-timestamp")]
+        crate::timestamp::set_source::<Self>(self.timer.as_timer0(), self.timer.shared_period());
     }
 
     fn set_compare(&mut self, val: Self::Instant) {
         let now = self.now();
-        let max = u32::MAX;
 
         let dur = match val.checked_duration_since(now) {
-            None => 1, // in the past
-            Some(x) => max.min(x.ticks()).max(1),
+            None => {
+                // in the past
+                #[cfg(feature = "stats")]
+                self.timer.shared_stats().past_deadline();
+                1
+            }
+            Some(x) => x.ticks().max(1),
         };
 
-        self.timer.as_timer0().cc[0]
-            .write(|w| unsafe { w.cc().bits((dur & (Self::OVFLOW_REGISTER)) as u32) });
+        // Only the lower 32 bits can be compared. Targets further away than a counter period
+        // fire early, the timer queue will then set the compare value again.
+        let target = now.ticks() + dur;
+        #[cfg(feature = "stats")]
+        {
+            self.compare_at = Some(target);
+        }
+        self.timer
+            .as_timer0()
+            .set_cc(Self::CC_COMPARE, target as u32);
     }
 
     fn clear_compare_flag(&mut self) {
         if self.is_compare_match() {
+            #[cfg(feature = "stats")]
+            {
+                let now = self.now().ticks();
+                self.timer
+                    .shared_stats()
+                    .compare_event(&mut self.compare_at, now);
+            }
             self.clear_compare_match_flag();
             trace!("Compare flag cleared");
         }
@@ -138,11 +231,19 @@ impl<INSTANCE: Instance> Monotonic for NrfMonotonic<INSTANCE> {
 
     fn on_interrupt(&mut self) {
         // self.clear_compare_flag();
-        if self.is_overflow() {
-            self.clear_overflow_flag();
-            self.ovf += Self::OVFLOW_INCREMENT;
-            debug!("Overflow, flag: {:x}", self.ovf);
+        if self.is_next_period() {
+            self.clear_next_period_flag();
+            self.period += 1;
+            self.timer.shared_period().set(self.period);
+            self.timer
+                .as_timer0()
+                .set_cc(Self::CC_PERIOD, Self::next_period_start(self.period));
+            #[cfg(feature = "stats")]
+            self.timer.shared_stats().overflow();
+            debug!("Overflow, period: {}", self.period);
         }
+
+        handle_alarms(self.timer.as_timer0(), Self::FIRST_ALARM, &self.callbacks);
     }
 
     fn enable_timer(&mut self) {
@@ -161,13 +262,12 @@ impl<INSTANCE: Instance> Monotonic for NrfMonotonic<INSTANCE> {
         }
     }
 
-    type Instant = fugit::TimerInstantU32<{ TIMER_HZ }>;
-    type Duration = fugit::TimerDurationU32<{ TIMER_HZ }>;
+    type Instant = fugit::TimerInstantU64<HZ>;
+    type Duration = fugit::TimerDurationU64<HZ>;
 
     fn now(&mut self) -> Self::Instant {
-        let t0 = self.timer.as_timer0();
-        t0.tasks_capture[Self::CC_NOW].write(|w| w.tasks_capture().set_bit());
-        Self::Instant::from_ticks(t0.cc[Self::CC_NOW].read().bits())
+        let cnt = self.timer.as_timer0().capture(Self::CC_NOW);
+        Self::Instant::from_ticks(calc_now::<32>(self.period, cnt))
     }
 
     fn zero() -> Self::Instant {
@@ -176,13 +276,136 @@ impl<INSTANCE: Instance> Monotonic for NrfMonotonic<INSTANCE> {
 }
 
 #[inline(always)]
-fn disable_interrupts(t0: &TimerRegister) {
-    t0.intenclr
-        .write(|w| w.compare1().set_bit().compare3().set_bit());
+fn disable_interrupts(t0: &impl TimerRegisters) {
+    t0.disable_compare_interrupt(0);
+    t0.disable_compare_interrupt(2);
 }
 
 #[inline(always)]
-fn enable_interrupts(t0: &TimerRegister) {
-    t0.intenset
-        .write(|w| w.compare0().set_bit().compare2().set_bit());
+fn enable_interrupts(t0: &impl TimerRegisters) {
+    t0.enable_compare_interrupt(0);
+    t0.enable_compare_interrupt(2);
+}
+
+impl<INSTANCE: Instance32, const HZ: u32> Split for NrfMonotonic<INSTANCE, HZ> {
+    type Registers = INSTANCE::Registers;
+
+    const HZ: u32 = HZ;
+    const COUNTER_BITS: u32 = 32;
+    const MIN_DISTANCE: u64 = 1;
+
+    #[inline(always)]
+    fn counter(registers: &Self::Registers) -> u32 {
+        registers.capture(Self::CC_NOW)
+    }
+
+    #[inline(always)]
+    fn ticks(instant: Self::Instant) -> u64 {
+        instant.ticks()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{handle_interrupt, wait_until, SimClock, SimTimer, Simulated};
+
+    fn setup(sim: &'static SimTimer<4>) -> NrfMonotonic<&'static SimTimer<4>> {
+        let mut mono = NrfMonotonic::new(sim);
+        unsafe { mono.reset() };
+        mono
+    }
+
+    #[test]
+    fn prescaler_follows_hz() {
+        let sim = SimTimer::<4>::new();
+        let _mono = NrfMonotonic::<_, 16_000_000>::new(sim);
+        assert_eq!(sim.prescaler(), 0);
+        let _mono = NrfMonotonic::<_, 31_250>::new(sim);
+        assert_eq!(sim.prescaler(), 9);
+    }
+
+    #[test]
+    fn disable_timer_masks_its_interrupts() {
+        let sim = SimTimer::new();
+        let mut mono = setup(sim);
+        mono.disable_timer();
+        for n in 0..4 {
+            assert!(!sim.is_compare_interrupt_enabled(n));
+        }
+        mono.enable_timer();
+        assert!(sim.is_compare_interrupt_enabled(0));
+        assert!(sim.is_compare_interrupt_enabled(2));
+    }
+
+    #[test]
+    fn now_counts_across_periods() {
+        let sim = SimTimer::new();
+        let mut mono = setup(sim);
+        let step = 0x3000_0001;
+        for i in 1..=20u64 {
+            sim.advance(step);
+            handle_interrupt(&mut mono, sim);
+            assert_eq!(mono.now().ticks(), i * step);
+        }
+    }
+
+    #[test]
+    fn now_tolerates_delayed_interrupt() {
+        let sim = SimTimer::new();
+        let mut mono = setup(sim);
+        let half = 0x8000_0000;
+        let mut elapsed = 0;
+        for _ in 0..6 {
+            // run into the next half period, then handle its interrupt almost half a period late
+            for step in [half - elapsed % half, half - 1] {
+                sim.advance(step);
+                elapsed += step;
+                assert_eq!(mono.now().ticks(), elapsed);
+            }
+            handle_interrupt(&mut mono, sim);
+            assert_eq!(mono.now().ticks(), elapsed);
+        }
+    }
+
+    #[test]
+    fn compare_fires_at_instant() {
+        let sim = SimTimer::new();
+        let mut mono = setup(sim);
+        for delay in [
+            1,
+            2,
+            1234,
+            0x7FFF_FFFF,
+            0x8000_0001,
+            0xFFFF_FFFF,
+            5 << 32 | 17,
+        ] {
+            let instant = mono.now()
+                + <NrfMonotonic<&'static SimTimer<4>> as Monotonic>::Duration::from_ticks(delay);
+            assert_eq!(wait_until(&mut mono, sim, instant), instant);
+        }
+    }
+
+    #[test]
+    fn compare_in_the_past() {
+        let sim = SimTimer::new();
+        let mut mono = setup(sim);
+        sim.advance(1000);
+        let now = mono.now();
+        let instant =
+            now - <NrfMonotonic<&'static SimTimer<4>> as Monotonic>::Duration::from_ticks(10);
+        assert_eq!(wait_until(&mut mono, sim, instant), now);
+    }
+
+    #[test]
+    fn try_new_needs_the_hfxo() {
+        let clock = SimClock::default();
+        clock.lfclk.set(true);
+        let mono = NrfMonotonic::<_>::try_new(SimTimer::<4>::new(), &clock);
+        assert_eq!(mono.err(), Some(ClockError::HfxoNotRunning));
+
+        clock.hfxo.set(true);
+        assert!(NrfMonotonic::<_>::try_new(SimTimer::<4>::new(), &clock).is_ok());
+    }
 }
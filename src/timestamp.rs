@@ -0,0 +1,156 @@
+//! Timestamps of the active monotonic for defmt
+//!
+//! `reset` of `NrfMonotonic`, `NrfMonotonic16` and `RtcMono` makes the monotonic the source of
+//! the timestamps, the one reset last wins. The time is read from the raw counter and the shared
+//! half-period count like `Alarm` does, so no `&mut` access to the monotonic is needed and the
+//! timestamp is correct in any interrupt, including the monotonic's own before it incremented
+//! the half-period count.
+//!
+//! ```ignore
+//! nrf_monotonic::defmt_timestamp!(us); // TIMER, seconds with microseconds
+//! nrf_monotonic::defmt_timestamp!(ms); // RTC, human-readable time with milliseconds
+//! ```
+//!
+//! Before the first `reset` the timestamp is 0.
+
+use crate::alarm::{SharedPeriod, Split};
+use crate::half_period::calc_now_bits;
+use core::cell::Cell;
+use critical_section::Mutex;
+
+/// Counter and half-period count of a monotonic
+#[derive(Clone, Copy)]
+struct Source {
+    registers: *const (),
+    counter: unsafe fn(*const ()) -> u32,
+    period: &'static SharedPeriod,
+    bits: u32,
+    hz: u32,
+}
+
+// SAFETY: `registers` points to the register block of a peripheral, which lives for the whole
+// program and is only read through `counter`
+unsafe impl Send for Source {}
+
+static SOURCE: Mutex<Cell<Option<Source>>> = Mutex::new(Cell::new(None));
+
+impl Source {
+    fn of<M: Split>(registers: &'static M::Registers, period: &'static SharedPeriod) -> Self {
+        Self {
+            registers: registers as *const M::Registers as *const (),
+            counter: counter::<M>,
+            period,
+            bits: M::COUNTER_BITS,
+            hz: M::HZ,
+        }
+    }
+
+    fn ticks(&self) -> u64 {
+        let period = self.period.get();
+        // SAFETY: `counter` belongs to the monotonic `registers` was taken from
+        let counter = unsafe { (self.counter)(self.registers) };
+        calc_now_bits(self.bits, period, counter)
+    }
+
+    /// Time since `reset` in units of 1 / `unit_hz` seconds, rounded down
+    fn time(&self, unit_hz: u64) -> u64 {
+        let (ticks, hz) = (self.ticks(), self.hz as u64);
+        ticks / hz * unit_hz + ticks % hz * unit_hz / hz
+    }
+}
+
+unsafe fn counter<M: Split>(registers: *const ()) -> u32 {
+    M::counter(&*(registers as *const M::Registers))
+}
+
+/// Make the monotonic `M` on `registers` the source of the timestamps
+pub(crate) fn set_source<M: Split>(
+    registers: &'static M::Registers,
+    period: &'static SharedPeriod,
+) {
+    let source = Source::of::<M>(registers, period);
+    critical_section::with(|cs| SOURCE.borrow(cs).set(Some(source)));
+}
+
+fn time(unit_hz: u64) -> u64 {
+    critical_section::with(|cs| SOURCE.borrow(cs).get()).map_or(0, |source| source.time(unit_hz))
+}
+
+/// Microseconds since the active monotonic was reset
+pub fn micros() -> u64 {
+    time(1_000_000)
+}
+
+/// Milliseconds since the active monotonic was reset
+pub fn millis() -> u64 {
+    time(1_000)
+}
+
+/// Define the defmt timestamp from the active monotonic
+///
+/// `us` shows seconds with microseconds (`{=u64:us}`), for the TIMER monotonics. `ms` shows
+/// human-readable time with milliseconds (`{=u64:tms}`), enough for the 30.5µs ticks of the RTC.
+#[macro_export]
+macro_rules! defmt_timestamp {
+    (us) => {
+        ::defmt::timestamp!("{=u64:us}", $crate::timestamp::micros());
+    };
+    (ms) => {
+        ::defmt::timestamp!("{=u64:tms}", $crate::timestamp::millis());
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtc_instance::RtcInstance;
+    use crate::sim::{SimRtc, SimTimer, Simulated};
+    use crate::timer_instance::Instance16;
+    use crate::{NrfMonotonic, RtcMono};
+    use rtic_monotonic::Monotonic;
+
+    type Timer = NrfMonotonic<&'static SimTimer<4>, 16_000_000>;
+    type Rtc = RtcMono<&'static SimRtc<4>>;
+
+    #[test]
+    fn timer_micros_follow_hz() {
+        let sim = SimTimer::<4>::new();
+        let mut mono = Timer::new(sim);
+        unsafe { mono.reset() };
+        let source = Source::of::<Timer>(sim.as_timer0(), sim.shared_period());
+
+        sim.advance(16 * 1234 + 15);
+        assert_eq!(source.time(1_000_000), 1234);
+    }
+
+    #[test]
+    fn rtc_millis_across_overflows() {
+        let sim = SimRtc::<4>::new();
+        let mut mono = Rtc::new(sim);
+        unsafe { mono.reset() };
+        let source = Source::of::<Rtc>(sim.as_rtc0(), sim.shared_period());
+
+        // the half-period interrupt is pending, as inside the monotonic's own interrupt
+        for _ in 0..5 {
+            sim.advance(0x80_0000);
+            assert_eq!(source.ticks(), mono.now().ticks());
+            mono.on_interrupt();
+        }
+        assert_eq!(source.time(1_000), 5 * 0x80_0000 * 1_000 / 32_768);
+    }
+
+    #[test]
+    fn long_uptime_does_not_overflow() {
+        let sim = SimTimer::<4>::new();
+        let mut mono = Timer::new(sim);
+        unsafe { mono.reset() };
+        let source = Source::of::<Timer>(sim.as_timer0(), sim.shared_period());
+
+        // about 100 days at 16MHz
+        for _ in 0..0x1_0000 {
+            sim.advance(0x8000_0000);
+            mono.on_interrupt();
+        }
+        assert_eq!(source.time(1_000_000), (0x8000_0000u64 << 16) / 16);
+    }
+}
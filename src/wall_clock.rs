@@ -0,0 +1,248 @@
+//! UTC time on top of a 64-bit monotonic
+//!
+//! `WallClock` maps the instants of a monotonic to microseconds since the Unix epoch. It only
+//! keeps the mapping, so it works with any monotonic ticking at `HZ`, including one owned by
+//! RTIC: `utc_at` converts an instant from `monotonics::now()`, `now_utc` reads a borrowed
+//! monotonic like `Delay` does.
+//!
+//! ```ignore
+//! let mut wall = WallClock::<32_768>::new();
+//! // UTC received from the gateway, with the instant it arrived at
+//! wall.set_utc(1_700_000_000, received_at)?;
+//! let timestamp = wall.utc_at(monotonics::now());
+//! ```
+//!
+//! The first `set_utc` sets the time. Later ones slew it, so the UTC time neither jumps nor runs
+//! backwards: the difference to the new UTC is corrected within `SLEW_SECONDS`, at `SLEW_PPM` or
+//! faster. The rate is capped at 100%, the UTC time runs at most twice as fast and holds while a
+//! difference larger than `SLEW_SECONDS` backwards is corrected.
+
+use core::convert::TryFrom;
+use fugit::TimerInstantU64;
+use rtic_monotonic::Monotonic;
+
+/// Lowest rate at which a re-sync is corrected, in microseconds per second
+pub const SLEW_PPM: u32 = 500;
+
+/// Time a re-sync correction is spread over, corrections of up to 128ms take it at `SLEW_PPM`
+pub const SLEW_SECONDS: u32 = 256;
+
+/// Highest correction rate, the UTC time stands still while it slews backwards at this rate
+const MAX_SLEW_PPM: i128 = 1_000_000;
+
+/// `set_utc` was given an instant before the one of the last sync
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct StaleSync;
+
+/// The mapping of the last `set_utc`
+#[derive(Clone, Copy, Debug)]
+struct Sync {
+    /// Monotonic time of the sync in µs
+    at: i128,
+    /// UTC minus monotonic time in µs, before the correction
+    offset: i128,
+    /// Correction still to be slewed in µs
+    error: i128,
+    /// Rate of the correction in µs per second
+    ppm: i128,
+}
+
+impl Sync {
+    /// UTC minus monotonic time in µs at monotonic time `micros`
+    fn offset_at(&self, micros: i128) -> i128 {
+        let max = (micros - self.at).max(0) * self.ppm / 1_000_000;
+        self.offset + self.error.clamp(-max, max)
+    }
+}
+
+/// UTC time of the instants of a monotonic running at `HZ`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WallClock<const HZ: u32> {
+    sync: Option<Sync>,
+}
+
+impl<const HZ: u32> WallClock<HZ> {
+    /// A wall clock without UTC time until `set_utc`
+    pub const fn new() -> Self {
+        Self { sync: None }
+    }
+
+    /// It was `unix_seconds` UTC at `at`
+    ///
+    /// Sets the time the first time, and slews it from the current UTC time after. An `at`
+    /// before the one of the last sync is rejected, the mapping is left unchanged.
+    pub fn set_utc(&mut self, unix_seconds: u64, at: TimerInstantU64<HZ>) -> Result<(), StaleSync> {
+        let micros = Self::micros(at);
+        let offset = unix_seconds as i128 * 1_000_000 - micros;
+        self.sync = Some(match self.sync {
+            Some(sync) if micros < sync.at => return Err(StaleSync),
+            Some(sync) => {
+                let current = sync.offset_at(micros);
+                let error = offset - current;
+                Sync {
+                    at: micros,
+                    offset: current,
+                    error,
+                    ppm: (error.abs() / SLEW_SECONDS as i128).clamp(SLEW_PPM as i128, MAX_SLEW_PPM),
+                }
+            }
+            None => Sync {
+                at: micros,
+                offset,
+                error: 0,
+                ppm: SLEW_PPM as i128,
+            },
+        });
+        Ok(())
+    }
+
+    /// Microseconds since the Unix epoch at `instant`, `None` before the first `set_utc`
+    pub fn utc_at(&self, instant: TimerInstantU64<HZ>) -> Option<u64> {
+        let micros = Self::micros(instant);
+        let sync = self.sync?;
+        u64::try_from(micros + sync.offset_at(micros)).ok()
+    }
+
+    /// Microseconds since the Unix epoch now, read from `mono`
+    pub fn now_utc<M>(&self, mono: &mut M) -> Option<u64>
+    where
+        M: Monotonic<Instant = TimerInstantU64<HZ>>,
+    {
+        self.utc_at(mono.now())
+    }
+
+    /// The correction still to be slewed at `instant` in µs, positive if the clock is behind
+    pub fn slew_remaining(&self, instant: TimerInstantU64<HZ>) -> i64 {
+        self.sync.map_or(0, |sync| {
+            let micros = Self::micros(instant);
+            (sync.offset + sync.error - sync.offset_at(micros)) as i64
+        })
+    }
+
+    /// Monotonic time of `instant` in µs
+    fn micros(instant: TimerInstantU64<HZ>) -> i128 {
+        instant.ticks() as i128 * 1_000_000 / HZ as i128
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtc_monotonic_v2::RtcMono;
+    use crate::sim::{SimRtc, Simulated};
+
+    const UNIX: u64 = 1_700_000_000;
+    const MICROS: u64 = UNIX * 1_000_000;
+
+    type Wall = WallClock<1_000_000>;
+
+    fn at(micros: u64) -> TimerInstantU64<1_000_000> {
+        TimerInstantU64::from_ticks(micros)
+    }
+
+    #[test]
+    fn first_sync_sets_the_time() {
+        let mut wall = Wall::new();
+        assert_eq!(wall.utc_at(at(5_000_000)), None);
+
+        wall.set_utc(UNIX, at(5_000_000)).unwrap();
+        assert_eq!(wall.utc_at(at(5_000_000)), Some(MICROS));
+        assert_eq!(wall.utc_at(at(6_500_000)), Some(MICROS + 1_500_000));
+        assert_eq!(wall.slew_remaining(at(6_500_000)), 0);
+    }
+
+    #[test]
+    fn resync_slews_forward() {
+        let mut wall = Wall::new();
+        wall.set_utc(UNIX, at(0)).unwrap();
+        // the clock is 10ms behind after 100s
+        wall.set_utc(UNIX + 100, at(99_990_000)).unwrap();
+        assert_eq!(wall.utc_at(at(99_990_000)), Some(MICROS + 99_990_000));
+
+        // 500µs per second
+        assert_eq!(wall.utc_at(at(101_990_000)), Some(MICROS + 101_991_000));
+        assert_eq!(wall.slew_remaining(at(101_990_000)), 9_000);
+
+        // corrected after 20s
+        assert_eq!(wall.utc_at(at(119_990_000)), Some(MICROS + 120_000_000));
+        assert_eq!(wall.utc_at(at(200_000_000)), Some(MICROS + 200_010_000));
+        assert_eq!(wall.slew_remaining(at(200_000_000)), 0);
+    }
+
+    #[test]
+    fn resync_slews_backward_without_running_backwards() {
+        let mut wall = Wall::new();
+        wall.set_utc(UNIX, at(0)).unwrap();
+        // the clock is 100ms ahead
+        wall.set_utc(UNIX, at(100_000)).unwrap();
+
+        let mut last = 0;
+        for second in 1..=202 {
+            let utc = wall.utc_at(at(second * 1_000_000)).unwrap();
+            assert!(utc > last);
+            last = utc;
+        }
+        assert_eq!(last, MICROS + 201_900_000);
+        assert_eq!(wall.slew_remaining(at(202_000_000)), 0);
+    }
+
+    #[test]
+    fn large_error_slews_faster() {
+        let mut wall = Wall::new();
+        wall.set_utc(UNIX, at(0)).unwrap();
+        // the clock is 1.28s behind, corrected at 5000µs per second
+        wall.set_utc(UNIX + 11, at(9_720_000)).unwrap();
+        assert_eq!(wall.utc_at(at(9_720_000)), Some(MICROS + 9_720_000));
+        assert_eq!(wall.utc_at(at(10_720_000)), Some(MICROS + 10_725_000));
+
+        let end = 9_720_000 + SLEW_SECONDS as u64 * 1_000_000;
+        assert_eq!(wall.utc_at(at(end)), Some(MICROS + end + 1_280_000));
+        assert_eq!(wall.slew_remaining(at(end)), 0);
+    }
+
+    #[test]
+    fn large_negative_correction_never_runs_backwards() {
+        let mut wall = Wall::new();
+        wall.set_utc(UNIX, at(0)).unwrap();
+        // the clock is 1s ahead, then 2000s ahead, which takes longer than `SLEW_SECONDS`
+        wall.set_utc(UNIX, at(1_000_000)).unwrap();
+        wall.set_utc(UNIX - 1_000, at(1_001_000_000)).unwrap();
+
+        let mut last = 0;
+        for tenth in 0..32_000 {
+            let utc = wall.utc_at(at(tenth * 100_000)).unwrap();
+            assert!(utc >= last);
+            last = utc;
+        }
+        // the time holds until the 2000s are corrected
+        let synced = wall.utc_at(at(1_001_000_000)).unwrap();
+        assert_eq!(synced, MICROS + 1_000_000_000);
+        assert_eq!(wall.utc_at(at(3_001_000_000)), Some(synced));
+        assert_eq!(wall.slew_remaining(at(3_001_000_000)), 0);
+        assert_eq!(wall.utc_at(at(3_100_000_000)), Some(MICROS + 1_099_000_000));
+    }
+
+    #[test]
+    fn stale_sync_is_rejected() {
+        let mut wall = Wall::new();
+        wall.set_utc(UNIX, at(5_000_000)).unwrap();
+        assert_eq!(wall.set_utc(UNIX + 100, at(4_999_999)), Err(StaleSync));
+        assert_eq!(wall.utc_at(at(6_000_000)), Some(MICROS + 1_000_000));
+
+        // the same instant is not stale
+        assert_eq!(wall.set_utc(UNIX, at(5_000_000)), Ok(()));
+    }
+
+    #[test]
+    fn now_from_a_monotonic() {
+        let sim = SimRtc::<4>::new();
+        let mut mono = RtcMono::new(sim);
+        unsafe { mono.reset() };
+        let mut wall = WallClock::<32_768>::new();
+        wall.set_utc(UNIX, mono.now()).unwrap();
+
+        sim.advance(3 * 32_768);
+        assert_eq!(wall.now_utc(&mut mono), Some(MICROS + 3_000_000));
+    }
+}